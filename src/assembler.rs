@@ -1,13 +1,18 @@
 use std::collections::HashMap;
 
+use crate::cell::CellWidth;
+use crate::debug::DebugInfo;
+use crate::header::Header;
 use crate::opcode::OpCode;
 
 #[derive(Debug, Clone)]
 pub enum TokenKind {
     Op(OpCode),
-    Int(u8),
+    Int(u64), // 負の数は2の補数。出力するときに即値の幅に収まるか確かめる
     LabelDef(String),
     LabelRef(String),
+    Args(Vec<String>),   // .ARGS a b (a を先に積む)
+    Locals(Vec<String>), // .LOCALS x y
 }
 
 #[derive(Debug, Clone)]
@@ -32,7 +37,8 @@ pub fn parse(input: String) -> Result<Vec<Token>, String> {
 
         let code = split_token.split(';').next().unwrap_or("");
 
-        for token in code.split_whitespace() {
+        let mut words = code.split_whitespace();
+        while let Some(token) = words.next() {
             // ignore comment
             if token.starts_with(';') {
                 break;
@@ -40,6 +46,21 @@ pub fn parse(input: String) -> Result<Vec<Token>, String> {
 
             let token = token.to_uppercase();
 
+            // case .ARGS / .LOCALS (行の残りがすべて名前)
+            if token == ".ARGS" || token == ".LOCALS" {
+                let names = words.by_ref().map(|word| word.to_uppercase()).collect();
+                tokens.push(Token {
+                    kind: if token == ".ARGS" {
+                        TokenKind::Args(names)
+                    } else {
+                        TokenKind::Locals(names)
+                    },
+                    line,
+                });
+
+                continue;
+            }
+
             // case OpCode
             if let Some(opcode) = OpCode::from_str(token.as_str()) {
                 tokens.push(Token {
@@ -53,7 +74,7 @@ pub fn parse(input: String) -> Result<Vec<Token>, String> {
             // case number such as 0x...
             if token.starts_with("0X") {
                 let token = token.strip_prefix("0X").unwrap();
-                match u8::from_str_radix(token, 16) {
+                match u64::from_str_radix(token, 16) {
                     Ok(number) => {
                         tokens.push(Token {
                             kind: TokenKind::Int(number),
@@ -69,7 +90,7 @@ pub fn parse(input: String) -> Result<Vec<Token>, String> {
             }
 
             // case number such as 10(= radix 10)
-            if let Ok(number) = u8::from_str_radix(token.as_str(), 10) {
+            if let Ok(number) = token.parse::<u64>() {
                 tokens.push(Token {
                     kind: TokenKind::Int(number),
                     line,
@@ -78,6 +99,28 @@ pub fn parse(input: String) -> Result<Vec<Token>, String> {
                 continue;
             }
 
+            // case negative number such as -10 (2の補数で格納する)
+            if let Some(digits) = token.strip_prefix('-')
+                && digits.starts_with(|c: char| c.is_ascii_digit())
+            {
+                match token.parse::<i64>() {
+                    Ok(number) => {
+                        tokens.push(Token {
+                            kind: TokenKind::Int(number as u64),
+                            line,
+                        });
+                    }
+                    Err(_) => {
+                        return Err(format!(
+                            "Line {}: Invalid negative number '{}'",
+                            line, token
+                        ));
+                    }
+                };
+
+                continue;
+            }
+
             if token.ends_with(':') {
                 let token = token.strip_suffix(':').unwrap();
                 tokens.push(Token {
@@ -98,37 +141,384 @@ pub fn parse(input: String) -> Result<Vec<Token>, String> {
     Ok(tokens)
 }
 
-pub fn resolve(tokens: Vec<Token>) -> Result<Vec<u8>, String> {
+// ジャンプ先や即値のメモリアドレスの幅
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressMode {
+    #[default]
+    Auto, // ラベルのアドレスが 8bit に収まらなければワイドモードにする
+    Narrow,
+    Wide,
+}
+
+// 各トークンが出力するバイト数
+// 即値の幅は命令とヘッダで決まる (アドレスは 1 か 2 バイト、PUSH などの値はセルの幅)
+// 8bit セルのワイドモードでは PUSH <label> を PUSH 上位, PUSH 下位 に展開する
+fn sizes(tokens: &[Token], header: Header) -> Vec<usize> {
+    let mut prev: Option<OpCode> = None;
+
+    tokens
+        .iter()
+        .map(|token| {
+            let size = match (&token.kind, prev) {
+                (TokenKind::LabelDef(_) | TokenKind::Args(_) | TokenKind::Locals(_), _) => {
+                    return 0;
+                }
+                (TokenKind::Op(_), _) => 1,
+                (TokenKind::LabelRef(_), Some(OpCode::Push))
+                    if header.wide && header.cell_len() == 1 =>
+                {
+                    3
+                }
+                (_, Some(opcode)) if opcode.operand_len(header) > 0 => opcode.operand_len(header),
+                _ => 1,
+            };
+            prev = match &token.kind {
+                TokenKind::Op(opcode) => Some(*opcode),
+                _ => None,
+            };
+            size
+        })
+        .collect()
+}
+
+pub fn labels(tokens: &[Token], header: Header) -> HashMap<String, usize> {
     let mut labels: HashMap<String, usize> = HashMap::new();
     let mut address: usize = 0;
 
-    for token in &tokens {
-        match &token.kind {
-            TokenKind::LabelDef(label) => {
-                labels.insert(label.clone(), address);
-            }
-            _ => address += 1,
+    for (token, size) in tokens.iter().zip(sizes(tokens, header)) {
+        if let TokenKind::LabelDef(label) = &token.kind {
+            labels.insert(label.clone(), address);
         }
+        address += size;
     }
 
-    let mut binary: Vec<u8> = Vec::new();
-    binary.extend_from_slice(&[0x00, b'T', b'W', b'N']);
+    labels
+}
 
-    for token in &tokens {
-        match &token.kind {
-            TokenKind::LabelDef(_) => {}
-            TokenKind::LabelRef(label) => match labels.get(label) {
-                Some(value) => binary.push(*value as u8),
-                None => return Err(format!("Line {}: Unknown label '{}'", token.line, label)),
-            },
+// mode が Auto なら、8bit のアドレスで足りるか確かめて決める
+pub fn is_wide(tokens: &[Token], mode: AddressMode, cell: CellWidth) -> bool {
+    match mode {
+        AddressMode::Narrow => false,
+        AddressMode::Wide => true,
+        AddressMode::Auto => {
+            let narrow = Header { wide: false, cell };
+            let labels = labels(tokens, narrow);
+            let too_far = labels.values().any(|addr| *addr > u8::MAX as usize);
+            let too_large = tokens.windows(2).any(|pair| {
+                matches!(pair[0].kind, TokenKind::Op(opcode) if opcode.has_address_operand())
+                    && matches!(pair[1].kind, TokenKind::Int(n) if n > u8::MAX as u64)
+            });
+            too_far || too_large
+        }
+    }
+}
+
+pub fn debug_info(tokens: &[Token], header: Header) -> DebugInfo {
+    let symbols = labels(tokens, header)
+        .into_iter()
+        .map(|(label, address)| (address, label))
+        .collect();
+
+    let mut lines: Vec<(usize, usize)> = Vec::new();
+    let mut address: usize = 0;
+
+    for (token, size) in tokens.iter().zip(sizes(tokens, header)) {
+        if let TokenKind::Op(_) = token.kind {
+            lines.push((address, token.line));
+        }
+        address += size;
+    }
+
+    DebugInfo::new(symbols, lines)
+}
+
+// LOADL / STOREL に名前で書けるローカル変数と引数
+// .ARGS / .LOCALS が続けて現れるとまとめて1つのサブルーチンの宣言になり、次の宣言まで有効
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    args: Vec<String>,
+    locals: Vec<String>,
+}
+impl Scope {
+    // ローカル変数は 0 から、引数は最後に積んだものが -1
+    fn offset(&self, name: &str) -> Option<i64> {
+        if let Some(i) = self.locals.iter().position(|local| local == name) {
+            return Some(i as i64);
+        }
+
+        let i = self.args.iter().position(|arg| arg == name)?;
+        Some(i as i64 - self.args.len() as i64)
+    }
+}
+
+// size バイトに符号なし・符号付きのどちらかとして収まるか
+fn fits(value: u64, size: usize) -> bool {
+    if size >= 8 {
+        return true;
+    }
+
+    let bits = size as u32 * 8;
+    match value as i64 {
+        n if n < 0 => n >= -(1 << (bits - 1)),
+        _ => value < 1 << bits,
+    }
+}
+
+pub fn resolve(tokens: Vec<Token>, header: Header) -> Result<Vec<u8>, String> {
+    let labels = labels(&tokens, header);
+
+    let mut binary: Vec<u8> = header.to_bytes();
+    let mut scope = Scope::default();
+    let mut declaring = false;
+    let mut prev: Option<OpCode> = None;
+
+    for (token, size) in tokens.iter().zip(sizes(&tokens, header)) {
+        let is_local = matches!(prev, Some(OpCode::LoadL | OpCode::StoreL));
+        let pending = prev.filter(|opcode| opcode.operand_len(header) > 0);
+        prev = match &token.kind {
+            TokenKind::Op(opcode) => Some(*opcode),
+            _ => None,
+        };
+
+        // 宣言以外のトークンを挟んだら新しいサブルーチンの宣言とする
+        let is_declaration = matches!(token.kind, TokenKind::Args(_) | TokenKind::Locals(_));
+        if is_declaration && !declaring {
+            scope = Scope::default();
+        }
+        declaring = is_declaration;
+
+        let value = match &token.kind {
+            TokenKind::LabelDef(_) => continue,
+            TokenKind::Args(names) => {
+                scope.args = names.clone();
+                continue;
+            }
+            TokenKind::Locals(names) => {
+                scope.locals = names.clone();
+                continue;
+            }
+            // 即値を取る命令の直後に命令が来たら即値が抜けている (ラベル名が命令と同じ場合など)
             TokenKind::Op(opcode) => {
+                if let Some(pending) = pending {
+                    return Err(missing_operand(token.line, pending));
+                }
                 binary.push(*opcode as u8);
+                continue;
             }
-            TokenKind::Int(number) => {
-                binary.push(*number as u8);
+            TokenKind::LabelRef(name) if is_local => match scope.offset(name) {
+                Some(offset) => offset as u64,
+                None => return Err(format!("Line {}: Unknown local '{}'", token.line, name)),
+            },
+            TokenKind::LabelRef(label) => match labels.get(label) {
+                Some(value) => *value as u64,
+                None => return Err(format!("Line {}: Unknown label '{}'", token.line, label)),
+            },
+            TokenKind::Int(number) => *number,
+        };
+
+        match size {
+            // PUSH <label> は上位バイト, PUSH, 下位バイト (直前の PUSH と合わせて2命令)
+            3 => binary.extend_from_slice(&[(value >> 8) as u8, OpCode::Push as u8, value as u8]),
+            _ if !fits(value, size) => {
+                // 負の数はそのまま表示する
+                let shown = match value as i64 {
+                    n if n < 0 => n.to_string(),
+                    _ => format!("0x{:X}", value),
+                };
+                let hint = match size {
+                    1 => " (use wide address mode or a wider cell)",
+                    _ => "",
+                };
+                return Err(format!(
+                    "Line {}: Value {} does not fit in {} byte(s){}",
+                    token.line, shown, size, hint
+                ));
             }
+            _ => binary.extend_from_slice(&value.to_le_bytes()[..size]),
         }
     }
+    if let (Some(opcode), Some(token)) = (prev, tokens.last())
+        && opcode.operand_len(header) > 0
+    {
+        return Err(missing_operand(token.line, opcode));
+    }
 
     Ok(binary)
 }
+
+fn missing_operand(line: usize, opcode: OpCode) -> String {
+    format!("Line {}: Missing operand for {}", line, opcode.name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negative_literal() {
+        let tokens = parse("PUSH -1\nPUSH -128\n-ROT".to_string()).unwrap();
+        assert!(matches!(tokens[1].kind, TokenKind::Int(n) if n as i64 == -1));
+        assert!(matches!(tokens[4].kind, TokenKind::Op(OpCode::NRot)));
+        assert_eq!(
+            &resolve(tokens, Header::default()).unwrap()[4..],
+            &[
+                OpCode::Push as u8,
+                0xFF,
+                OpCode::Push as u8,
+                0x80,
+                OpCode::NRot as u8
+            ]
+        );
+
+        // 範囲はセルの幅で決まる
+        let tokens = parse("PUSH -129".to_string()).unwrap();
+        assert!(resolve(tokens.clone(), Header::default()).is_err());
+        let header = Header {
+            cell: CellWidth::U16,
+            ..Default::default()
+        };
+        assert_eq!(
+            &resolve(tokens, header).unwrap()[5..],
+            &[OpCode::Push as u8, 0x7F, 0xFF]
+        );
+    }
+
+    #[test]
+    fn test_cell_width() {
+        // PUSH と ADDI の即値はセルの幅、SHLI の即値とアドレスは変わらない
+        let tokens = parse("PUSH 0x12345678\nADDI -1\nSHLI 4\nJMZ END\nEND:".to_string()).unwrap();
+        let header = Header {
+            cell: CellWidth::U32,
+            ..Default::default()
+        };
+        assert_eq!(labels(&tokens, header)["END"], 5 + 5 + 2 + 2);
+
+        let binary = resolve(tokens, header).unwrap();
+        assert_eq!(&binary[..5], &[0x00, b'T', b'W', b'E', 0x04]);
+        assert_eq!(
+            &binary[5..],
+            &[
+                OpCode::Push as u8,
+                0x78,
+                0x56,
+                0x34,
+                0x12,
+                OpCode::AddI as u8,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFF,
+                OpCode::ShlI as u8,
+                4,
+                OpCode::Jmz as u8,
+                14,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_named_locals() {
+        let source = "
+            SUM:
+                .ARGS A B
+                .LOCALS T
+                ENTER 1
+                LOADL A
+                LOADL B
+                ADD
+                STOREL T
+                LOADL T
+                STOREL a
+                LEAVE
+                RET
+            NEG1:
+                .ARGS X
+                LOADL X
+                RET
+        ";
+        let tokens = parse(source.to_string()).unwrap();
+        let binary = resolve(tokens, Header::default()).unwrap();
+        assert_eq!(
+            &binary[4..],
+            &[
+                OpCode::Enter as u8,
+                1,
+                OpCode::LoadL as u8,
+                -2i8 as u8,
+                OpCode::LoadL as u8,
+                -1i8 as u8,
+                OpCode::Add as u8,
+                OpCode::StoreL as u8,
+                0,
+                OpCode::LoadL as u8,
+                0,
+                OpCode::StoreL as u8,
+                -2i8 as u8,
+                OpCode::Leave as u8,
+                OpCode::Ret as u8,
+                OpCode::LoadL as u8,
+                -1i8 as u8,
+                OpCode::Ret as u8,
+            ]
+        );
+
+        // 次の宣言からは前のサブルーチンの名前は使えない
+        let tokens = parse(".ARGS A\nRET\n.ARGS B\nLOADL A".to_string()).unwrap();
+        assert!(resolve(tokens, Header::default()).is_err());
+    }
+
+    #[test]
+    fn test_missing_operand() {
+        // 命令と同じ名前のラベルは命令として読まれるので即値が抜ける
+        let tokens = parse("CALL ENTER\nFIN\nENTER:\nRET".to_string()).unwrap();
+        assert_eq!(
+            resolve(tokens, Header::default()),
+            Err("Line 1: Missing operand for CALL".to_string())
+        );
+
+        let tokens = parse("PUSH".to_string()).unwrap();
+        assert!(resolve(tokens, Header::default()).is_err());
+    }
+
+    #[test]
+    fn test_wide_mode() {
+        // 256 バイト目以降のラベルがあれば自動でワイドモードになる
+        let mut source = String::from("JMZ FAR\nPUSH FAR\nSTOREI 0x1234\n");
+        source.push_str(&"POP\n".repeat(300));
+        source.push_str("FAR:\nFIN\n");
+        let tokens = parse(source).unwrap();
+        let wide = Header {
+            wide: true,
+            ..Default::default()
+        };
+        assert!(is_wide(&tokens, AddressMode::Auto, CellWidth::U8));
+        assert!(resolve(tokens.clone(), Header::default()).is_err());
+
+        let binary = resolve(tokens.clone(), wide).unwrap();
+        let far = labels(&tokens, wide)["FAR"];
+        assert_eq!(far, 3 + 4 + 3 + 300);
+        assert_eq!(&binary[..5], &[0x00, b'T', b'W', b'E', 0x01]);
+        assert_eq!(
+            &binary[5..15],
+            &[
+                OpCode::Jmz as u8,
+                far as u8,
+                (far >> 8) as u8,
+                OpCode::Push as u8,
+                (far >> 8) as u8,
+                OpCode::Push as u8,
+                far as u8,
+                OpCode::StoreI as u8,
+                0x34,
+                0x12,
+            ]
+        );
+
+        let tokens = parse("JMZ END\nEND:\nFIN".to_string()).unwrap();
+        assert!(!is_wide(&tokens, AddressMode::Auto, CellWidth::U8));
+        assert_eq!(
+            &resolve(tokens, Header::default()).unwrap()[..4],
+            &[0x00, b'T', b'W', b'N']
+        );
+    }
+}
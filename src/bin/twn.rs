@@ -4,7 +4,7 @@ fn main() {
     let args: Vec<String> = std::env::args().skip(1).collect();
 
     if args.is_empty() {
-        eprintln!("Usage: twn <FILE_BASE_NAME>");
+        eprintln!("Usage: twn <FILE_BASE_NAME> [ARGS...]");
         exit(1);
     }
 
@@ -33,7 +33,10 @@ fn main() {
 
     // 2. twnvm (VM) の実行
     println!("[twn] Running VM with {}...", binary_file);
-    let status_twnvm = Command::new("twnvm").arg(&binary_file).status();
+    let status_twnvm = Command::new("twnvm")
+        .arg(&binary_file)
+        .args(&args[1..])
+        .status();
 
     match status_twnvm {
         Ok(status) => {
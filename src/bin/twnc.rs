@@ -2,17 +2,46 @@ use std::io::Write;
 use std::path::Path;
 use std::process::exit;
 
-use twn::assembler;
+use twn::assembler::{self, AddressMode};
+use twn::cell::CellWidth;
+use twn::header::Header;
+
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "Usage: {} [--wide | --narrow] [--cell <TYPE>] <FILE.twn>",
+        program
+    );
+    eprintln!();
+    eprintln!("  --wide         Always use 16-bit addresses");
+    eprintln!("  --narrow       Always use 8-bit addresses (fails if a label does not fit)");
+    eprintln!("  --cell <TYPE>  Type of stack and memory cells (u8, u16, u32 or i64; default u8)");
+    eprintln!("By default 16-bit addresses are used only when a label is beyond 0xFF.");
+    exit(1);
+}
 
 fn main() {
     let args = std::env::args().collect::<Vec<String>>();
 
-    if args.len() < 2 {
-        eprintln!("Usage: {} <FILE.twn>", args[0]);
-        exit(1);
+    let mut mode = AddressMode::Auto;
+    let mut cell = CellWidth::default();
+    let mut input_file: Option<&String> = None;
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--wide" => mode = AddressMode::Wide,
+            "--narrow" => mode = AddressMode::Narrow,
+            "--cell" => match iter.next().and_then(|name| CellWidth::from_name(name)) {
+                Some(width) => cell = width,
+                None => usage(&args[0]),
+            },
+            _ if input_file.is_none() && !arg.starts_with("--") => input_file = Some(arg),
+            _ => usage(&args[0]),
+        }
     }
+    let Some(input_file) = input_file else {
+        usage(&args[0]);
+    };
 
-    let input_file = &args[1];
     let input = std::fs::read_to_string(input_file).expect("Input is empty");
 
     let output_path = Path::new(input_file).with_extension("twnd");
@@ -31,7 +60,14 @@ fn main() {
         }
     };
 
-    let binary = match assembler::resolve(tokens) {
+    let header = Header {
+        wide: assembler::is_wide(&tokens, mode, cell),
+        cell,
+    };
+    let mut debug_info = assembler::debug_info(&tokens, header);
+    debug_info.source = Some(input_file.clone());
+
+    let binary = match assembler::resolve(tokens, header) {
         Ok(b) => b,
         Err(msg) => {
             eprintln!("Assemble Error -> {}", msg);
@@ -44,6 +80,12 @@ fn main() {
         exit(1);
     }
 
+    let debug_path = Path::new(input_file).with_extension("twndbg");
+    if let Err(e) = std::fs::write(&debug_path, debug_info.serialize()) {
+        eprintln!("Failed to write debug info: {}", e);
+        exit(1);
+    }
+
     println!(
         "Successfully assembled to {}",
         Path::new(input_file).with_extension("twnd").display()
@@ -1,23 +1,432 @@
-use std::io::{stdin, stdout};
+use std::collections::HashMap;
+use std::io::{Stderr, stderr, stdin, stdout};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
-use twn::vm::VM;
+use twn::cell::{Cell, CellWidth};
+use twn::clock::VirtualClock;
+use twn::coverage::Coverage;
+use twn::debug::DebugInfo;
+use twn::dump::{DumpFormat, json_string};
+use twn::fs::HostFileSystem;
+use twn::header::Header;
+use twn::history::History;
+use twn::profile::Profile;
+use twn::snapshot::snapshot_header;
+use twn::verify::verify;
+use twn::vm::{ArithMode, VM, VmConfig};
+
+// 終了コード
+// 0-199 はプログラムが EXIT / FIN で返したもの、実行時エラーは VmErrorKind::exit_code (210-222)
+// EXIT は 200 以上も返せるので、区別したいときは --error-format json の出力を見る
+const EXIT_USAGE: i32 = 200; // コマンドラインの誤り
+const EXIT_LOAD: i32 = 201; // 入力ファイルを読めない・形式が違う
+const EXIT_VERIFY: i32 = 202; // バイトコードの検査に失敗した
+const EXIT_LIMIT: i32 = 203; // --max-steps で止まった
+
+#[derive(Default)]
+struct Options {
+    input: String,
+    resume: bool,
+    flamegraph: Option<PathBuf>,
+    coverage: Option<PathBuf>,
+    lcov: Option<PathBuf>,
+    coverage_summary: bool,
+    snapshot: Option<PathBuf>,
+    max_steps: Option<u64>,
+    history: Option<usize>,
+    seed: Option<u64>,
+    virtual_clock: bool,
+    allow_env: Vec<String>,
+    fs_root: Option<PathBuf>,
+    fs_write: bool,
+    dump_format: DumpFormat,
+    arith_mode: ArithMode,
+    error_format: DumpFormat, // エラーの出力形式 (DUMP と同じ text / json)
+    args: Vec<String>,
+}
+
+// 値を取らないオプション
+const FLAGS: [&str; 3] = ["--coverage-summary", "--fs-write", "--virtual-clock"];
+
+// コマンドラインの誤りを --error-format に従って出力して終了する
+fn usage(program: &str, format: DumpFormat, message: &str) -> ! {
+    if format == DumpFormat::Json {
+        fail(format, "usage", "", message.to_string(), EXIT_USAGE);
+    }
+
+    eprintln!("Error: {}", message);
+    eprintln!();
+    eprintln!("Usage: {} [OPTIONS] <FILE.twnd> [ARGS...]", program);
+    eprintln!(
+        "       {} [OPTIONS] --resume <FILE.twns> [ARGS...]",
+        program
+    );
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!(
+        "  --flamegraph <FILE>  Write executed instruction counts per call stack (folded format)"
+    );
+    eprintln!("  --coverage <FILE>    Merge executed addresses and branch outcomes into FILE");
+    eprintln!("  --lcov <FILE>        Write the (merged) coverage in lcov format");
+    eprintln!("  --coverage-summary   Print a per-line coverage summary to stderr");
+    eprintln!("  --resume <FILE>      Resume from a VM snapshot instead of bytecode");
+    eprintln!("  --snapshot <FILE>    Save a VM snapshot when the VM stops");
+    eprintln!("  --max-steps <N>      Stop after executing N instructions");
+    eprintln!(
+        "  --history <N>        Keep undo logs for the last N instructions and show them on error"
+    );
+    eprintln!("  --seed <N>           Seed the RANDOM syscall");
+    eprintln!("  --virtual-clock      Make SLEEP advance a virtual clock instead of waiting");
+    eprintln!("  --allow-env <NAME>   Let GETENV read the environment variable NAME");
+    eprintln!("  --fs-root <DIR>      Let file syscalls access files under DIR");
+    eprintln!("  --fs-write           Allow file syscalls to create and write files");
+    eprintln!("  --dump-format <FMT>  Output format of the DUMP syscall (text or json)");
+    eprintln!("  --arith <MODE>       Arithmetic overflow behavior (saturate, wrap or trap)");
+    eprintln!("  --error-format <FMT> Output format of VM errors (text or json)");
+    eprintln!();
+    eprintln!("Exit status:");
+    eprintln!("  0-199    Exit code of the program (EXIT / FIN)");
+    eprintln!("  {}      Invalid command line", EXIT_USAGE);
+    eprintln!("  {}      The input could not be loaded", EXIT_LOAD);
+    eprintln!("  {}      The bytecode failed verification", EXIT_VERIFY);
+    eprintln!("  {}      Stopped by --max-steps", EXIT_LIMIT);
+    eprintln!("  210-222  Runtime error (see README)");
+    exit(EXIT_USAGE);
+}
+
+// 引数の誤りも指定された形式で出力できるよう、--error-format だけを先に読む
+fn error_format(args: &[String]) -> DumpFormat {
+    let mut iter = args.iter().skip(1);
+
+    while let Some(arg) = iter.next() {
+        // ファイル名より後ろはプログラム引数
+        if !arg.starts_with("--") {
+            break;
+        }
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if FLAGS.contains(&name) {
+            continue;
+        }
+        let value = inline.or_else(|| iter.next().map(String::as_str));
+        if name == "--error-format"
+            && let Some(format) = value.and_then(DumpFormat::from_name)
+        {
+            return format;
+        }
+        if name == "--resume" {
+            break;
+        }
+    }
+
+    DumpFormat::Text
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut input: Option<String> = None;
+
+    let mut iter = args.iter().skip(1);
+
+    while let Some(arg) = iter.next() {
+        // --name=value の形も受け付ける
+        let (name, mut inline) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = |iter: &mut dyn Iterator<Item = &String>| match inline.take() {
+            Some(value) => Ok(value),
+            None => match iter.next() {
+                Some(value) => Ok(value.clone()),
+                None => Err(format!("Missing value for {}", name)),
+            },
+        };
+        let invalid = |value: &str| format!("Invalid value for {}: {}", name, value);
+
+        match name {
+            "--flamegraph" => options.flamegraph = Some(PathBuf::from(value(&mut iter)?)),
+            "--coverage" => options.coverage = Some(PathBuf::from(value(&mut iter)?)),
+            "--lcov" => options.lcov = Some(PathBuf::from(value(&mut iter)?)),
+            "--coverage-summary" => options.coverage_summary = true,
+            "--resume" => {
+                options.resume = true;
+                input = Some(value(&mut iter)?);
+                break;
+            }
+            "--snapshot" => options.snapshot = Some(PathBuf::from(value(&mut iter)?)),
+            "--max-steps" => {
+                let value = value(&mut iter)?;
+                options.max_steps = Some(value.parse::<u64>().map_err(|_| invalid(&value))?);
+            }
+            "--seed" => {
+                let value = value(&mut iter)?;
+                options.seed = Some(value.parse::<u64>().map_err(|_| invalid(&value))?);
+            }
+            "--allow-env" => options.allow_env.push(value(&mut iter)?),
+            "--fs-root" => options.fs_root = Some(PathBuf::from(value(&mut iter)?)),
+            "--fs-write" => options.fs_write = true,
+            "--virtual-clock" => options.virtual_clock = true,
+            "--arith" => {
+                let value = value(&mut iter)?;
+                options.arith_mode = ArithMode::from_name(&value).ok_or_else(|| invalid(&value))?;
+            }
+            "--dump-format" => {
+                let value = value(&mut iter)?;
+                options.dump_format =
+                    DumpFormat::from_name(&value).ok_or_else(|| invalid(&value))?;
+            }
+            "--error-format" => {
+                let value = value(&mut iter)?;
+                options.error_format =
+                    DumpFormat::from_name(&value).ok_or_else(|| invalid(&value))?;
+            }
+            "--history" => {
+                let value = value(&mut iter)?;
+                options.history = Some(value.parse::<usize>().map_err(|_| invalid(&value))?);
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+            _ => {
+                input = Some(arg.clone());
+                break;
+            }
+        }
+    }
+
+    // ファイル名より後ろはすべてプログラム引数
+    let program_args = iter.cloned().collect::<Vec<String>>();
+
+    match input {
+        Some(input) => Ok(Options {
+            input,
+            args: program_args,
+            ..options
+        }),
+        None => Err("Missing input file".to_string()),
+    }
+}
+
+// FILE.twnd と同じ場所にある FILE.twndbg を読み込む (無ければ空)
+fn load_debug_info(input: &str) -> DebugInfo {
+    let path = Path::new(input).with_extension("twndbg");
+
+    match std::fs::read_to_string(&path) {
+        Ok(text) => DebugInfo::parse(&text).unwrap_or_else(|e| {
+            eprintln!("Warning: Ignoring {} ({})", path.display(), e);
+            DebugInfo::default()
+        }),
+        Err(_) => DebugInfo::default(),
+    }
+}
+
+// 実行以外のエラーを --error-format に従って出力して終了する
+fn fail(format: DumpFormat, class: &str, prefix: &str, message: String, code: i32) -> ! {
+    match format {
+        DumpFormat::Text => eprintln!("{}{}", prefix, message),
+        DumpFormat::Json => eprintln!(
+            "{{\"error\":{},\"message\":{},\"exit_code\":{}}}",
+            json_string(class),
+            json_string(&message),
+            code
+        ),
+    }
+    exit(code);
+}
+
+// 今回の実行結果を --coverage のファイルに蓄積し、レポートを出力する
+fn write_coverage(options: &Options, coverage: &Coverage, debug_info: &DebugInfo, program: &[u8]) {
+    let mut merged = Coverage::new();
+
+    if let Some(path) = &options.coverage
+        && let Ok(text) = std::fs::read_to_string(path)
+    {
+        match Coverage::parse(&text) {
+            Ok(previous) => merged.merge(&previous),
+            Err(e) => eprintln!("Warning: Ignoring {} ({})", path.display(), e),
+        }
+    }
+    merged.merge(coverage);
+
+    if let Some(path) = &options.coverage
+        && let Err(e) = std::fs::write(path, merged.serialize())
+    {
+        eprintln!("Failed to write coverage data: {}", e);
+    }
+    if let Some(path) = &options.lcov
+        && let Err(e) = std::fs::write(path, merged.lcov(debug_info, program))
+    {
+        eprintln!("Failed to write lcov: {}", e);
+    }
+    if options.coverage_summary {
+        eprint!("{}", merged.summary(debug_info, program));
+    }
+}
 
 fn main() {
     let args = std::env::args().collect::<Vec<String>>();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(message) => usage(&args[0], error_format(&args), &message),
+    };
+
+    let tokens = match std::fs::read(&options.input) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            let message = format!("Failed to read {}: {}", options.input, e);
+            fail(options.error_format, "load", "Error: ", message, EXIT_LOAD)
+        }
+    };
+    let debug_info = load_debug_info(&options.input);
+
+    // ヘッダに書かれたセルの型で VM を作る
+    let header = if options.resume {
+        match snapshot_header(&tokens) {
+            Ok(header) => header,
+            Err(e) => fail(
+                options.error_format,
+                "load",
+                "Error: ",
+                e.to_string(),
+                EXIT_LOAD,
+            ),
+        }
+    } else {
+        match Header::parse(&tokens) {
+            Some((header, _)) => header,
+            None => {
+                let message = "Invalid file format: Magic number not found".to_string();
+                fail(options.error_format, "load", "Error: ", message, EXIT_LOAD)
+            }
+        }
+    };
 
-    if args.len() < 2 {
-        eprintln!("Usage: {} <FILE.twnd>", args[0]);
-        exit(1);
+    match header.cell {
+        CellWidth::U8 => run::<u8>(&options, tokens, debug_info),
+        CellWidth::U16 => run::<u16>(&options, tokens, debug_info),
+        CellWidth::U32 => run::<u32>(&options, tokens, debug_info),
+        CellWidth::I64 => run::<i64>(&options, tokens, debug_info),
     }
+}
 
-    let tokens = std::fs::read(&args[1]).expect("Input is empty");
+fn run<C: Cell>(options: &Options, tokens: Vec<u8>, debug_info: DebugInfo) -> ! {
+    let mut vm = if options.resume {
+        let snapshot = VM::<_, _, Stderr, C>::from_snapshot_with_debug_port(
+            &tokens,
+            stdin().lock(),
+            stdout().lock(),
+            stderr(),
+        );
+        match snapshot {
+            Ok(vm) => vm,
+            Err(e) => fail(
+                options.error_format,
+                "load",
+                "Error: ",
+                e.to_string(),
+                EXIT_LOAD,
+            ),
+        }
+    } else {
+        let config = VmConfig {
+            seed: options.seed,
+            ..Default::default()
+        };
+        VM::<_, _, Stderr, C>::create(tokens, stdin().lock(), stdout().lock(), stderr(), config)
+    };
+    if let Err(e) = verify(&vm.tokens, vm.header) {
+        fail(
+            options.error_format,
+            "verify",
+            "Verification error: ",
+            e.to_string(),
+            EXIT_VERIFY,
+        );
+    }
+    vm.args = options.args.clone();
+    vm.dump_format = options.dump_format;
+    vm.arith_mode = options.arith_mode;
+    vm.debug_info = Some(debug_info.clone());
+    vm.env = options
+        .allow_env
+        .iter()
+        .filter_map(|name| Some((name.clone(), std::env::var(name).ok()?)))
+        .collect::<HashMap<String, String>>();
+    if options.flamegraph.is_some() {
+        vm.profile = Some(Profile::new());
+    }
+    if let Some(root) = &options.fs_root {
+        match HostFileSystem::new(root, options.fs_write) {
+            Ok(fs) => vm.fs = Some(Box::new(fs)),
+            Err(e) => {
+                let message = format!("Invalid --fs-root {} ({})", root.display(), e);
+                fail(
+                    options.error_format,
+                    "usage",
+                    "Error: ",
+                    message,
+                    EXIT_USAGE,
+                )
+            }
+        }
+    }
+    if options.virtual_clock {
+        vm.clock = Box::new(VirtualClock::new());
+    }
+    if let Some(capacity) = options.history {
+        vm.history = Some(History::new(capacity));
+    }
+    if options.coverage.is_some() || options.lcov.is_some() || options.coverage_summary {
+        vm.coverage = Some(Coverage::new());
+    }
+
+    let result = match options.max_steps {
+        Some(steps) => vm.run_for(steps),
+        None => vm.run(),
+    };
+
+    if let Some(path) = &options.snapshot
+        && let Err(e) = std::fs::write(path, vm.snapshot())
+    {
+        eprintln!("Failed to write snapshot: {}", e);
+    }
 
-    let mut vm = VM::new(tokens, stdin().lock(), stdout().lock());
+    if let (Some(path), Some(profile)) = (&options.flamegraph, &vm.profile) {
+        let written = std::fs::File::create(path)
+            .and_then(|mut file| profile.write_folded(&debug_info, &mut file));
+        if let Err(e) = written {
+            eprintln!("Failed to write flamegraph: {}", e);
+        }
+    }
+
+    if let Some(coverage) = &vm.coverage {
+        write_coverage(options, coverage, &debug_info, &vm.tokens);
+    }
+
+    if let Err(e) = result {
+        if options.error_format == DumpFormat::Json {
+            eprint!("{}", vm.error_json(&e));
+            exit(e.kind.exit_code() as i32);
+        }
+
+        eprintln!("Error: {}", e);
+        eprint!("{}", vm.backtrace(&e));
+        if let Some(history) = &vm.history {
+            eprintln!("Recent instructions (oldest first):");
+            for pc in history.pcs() {
+                match debug_info.symbolize(pc) {
+                    Some((label, offset)) => eprintln!("  0x{:04X} {}+{}", pc, label, offset),
+                    None => eprintln!("  0x{:04X}", pc),
+                }
+            }
+        }
+        exit(e.kind.exit_code() as i32);
+    }
 
-    if let Err(e) = vm.run() {
-        eprintln!("Error: {} (at address 0x{:02X})", e, vm.pc);
-        std::process::exit(1);
+    if !vm.is_finished() {
+        let message = format!("Stopped after {} steps", options.max_steps.unwrap_or(0));
+        fail(options.error_format, "limit", "", message, EXIT_LIMIT);
     }
 
     std::process::exit(vm.exit_code as i32);
@@ -0,0 +1,203 @@
+use std::fmt::{Debug, Display};
+use std::ops::RangeInclusive;
+
+// スタックとメモリの1セルの型 (.twnd のヘッダに記録する)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+    I64,
+}
+impl CellWidth {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "u8" => Some(Self::U8),
+            "u16" => Some(Self::U16),
+            "u32" => Some(Self::U32),
+            "i64" => Some(Self::I64),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::U8 => "u8",
+            Self::U16 => "u16",
+            Self::U32 => "u32",
+            Self::I64 => "i64",
+        }
+    }
+
+    // ヘッダのフラグに書く番号
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Self::U8),
+            1 => Some(Self::U16),
+            2 => Some(Self::U32),
+            3 => Some(Self::I64),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::U16 => 2,
+            Self::U32 => 4,
+            Self::I64 => 8,
+        }
+    }
+
+    // 通常の算術演算と比較を符号付きで行うか
+    pub fn is_signed(self) -> bool {
+        self == Self::I64
+    }
+}
+impl Display for CellWidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// VM のスタックとメモリに入る値
+//
+// 値はビット列として持ち、命令ごとに符号なし・符号付きのどちらかとして読む
+// (ADD や LT などは u8 / u16 / u32 では符号なし、i64 では符号付き。SADD などは常に符号付き)
+// 計算は i128 で行い、結果をセルの幅に収める
+pub trait Cell: Copy + Eq + Ord + Default + Debug + Display + 'static {
+    const WIDTH: CellWidth;
+
+    fn to_bits(self) -> u64;
+    fn from_bits(bits: u64) -> Self; // 下位のビットだけを使う
+
+    fn bits() -> u32 {
+        Self::WIDTH.bytes() as u32 * 8
+    }
+
+    fn from_u8(n: u8) -> Self {
+        Self::from_bits(n as u64)
+    }
+
+    // 範囲外の値は2の補数で折り返す
+    fn wrap(n: i128) -> Self {
+        Self::from_bits(n as u64)
+    }
+
+    fn unsigned(self) -> i128 {
+        self.to_bits() as i128
+    }
+
+    fn signed(self) -> i128 {
+        let shift = 64 - Self::bits();
+        ((self.to_bits() << shift) as i64 >> shift) as i128
+    }
+
+    // 通常の演算での値
+    fn value(self) -> i128 {
+        if Self::WIDTH.is_signed() {
+            self.signed()
+        } else {
+            self.unsigned()
+        }
+    }
+
+    fn unsigned_range() -> RangeInclusive<i128> {
+        0..=(1i128 << Self::bits()) - 1
+    }
+
+    fn signed_range() -> RangeInclusive<i128> {
+        let half = 1i128 << (Self::bits() - 1);
+        -half..=half - 1
+    }
+
+    fn value_range() -> RangeInclusive<i128> {
+        if Self::WIDTH.is_signed() {
+            Self::signed_range()
+        } else {
+            Self::unsigned_range()
+        }
+    }
+}
+
+impl Cell for u8 {
+    const WIDTH: CellWidth = CellWidth::U8;
+
+    fn to_bits(self) -> u64 {
+        self as u64
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        bits as u8
+    }
+}
+
+impl Cell for u16 {
+    const WIDTH: CellWidth = CellWidth::U16;
+
+    fn to_bits(self) -> u64 {
+        self as u64
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        bits as u16
+    }
+}
+
+impl Cell for u32 {
+    const WIDTH: CellWidth = CellWidth::U32;
+
+    fn to_bits(self) -> u64 {
+        self as u64
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        bits as u32
+    }
+}
+
+impl Cell for i64 {
+    const WIDTH: CellWidth = CellWidth::I64;
+
+    fn to_bits(self) -> u64 {
+        self as u64
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        bits as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cell() {
+        assert_eq!(0xFFu8.unsigned(), 255);
+        assert_eq!(0xFFu8.signed(), -1);
+        assert_eq!(0xFFu8.value(), 255);
+        assert_eq!(u16::wrap(0x1_0001), 1);
+        assert_eq!(u32::unsigned_range(), 0..=u32::MAX as i128);
+
+        assert_eq!((-1i64).value(), -1);
+        assert_eq!((-1i64).unsigned(), u64::MAX as i128);
+        assert_eq!(i64::wrap(i64::MAX as i128 + 1), i64::MIN);
+        assert_eq!(i64::value_range(), i64::MIN as i128..=i64::MAX as i128);
+
+        for width in [
+            CellWidth::U8,
+            CellWidth::U16,
+            CellWidth::U32,
+            CellWidth::I64,
+        ] {
+            assert_eq!(CellWidth::from_code(width.code()), Some(width));
+            assert_eq!(CellWidth::from_name(width.name()), Some(width));
+        }
+    }
+}
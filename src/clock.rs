@@ -0,0 +1,61 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// TIME / SLEEP システムコールが使う時計 (VM ごと別のスレッドへ移せるよう Send に限る)
+pub trait Clock: Send {
+    // VM の開始からの経過時間
+    fn now(&self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+}
+
+// 実時間の時計 (通常の実行用)
+pub struct RealClock {
+    start: Instant,
+}
+impl RealClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+impl Default for RealClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Clock for RealClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+// 仮想時計 (テスト用)
+// SLEEP は実際には待たずに時刻だけを進める
+// clone したものは同じ時刻を共有するので、VM の外から advance できる
+#[derive(Debug, Clone, Default)]
+pub struct VirtualClock {
+    now: Arc<Mutex<Duration>>,
+}
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        self.advance(duration);
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::debug::DebugInfo;
+use crate::opcode::OpCode;
+
+// 実行された命令アドレスと条件分岐の結果
+//
+// 複数回の実行結果を merge して .twncov に保存できる
+// HIT <addr> <count>
+// BRANCH <addr> <taken> <not taken>
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    pub hits: BTreeMap<usize, u64>,
+    pub branches: BTreeMap<usize, (u64, u64)>,
+}
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn hit(&mut self, addr: usize) {
+        *self.hits.entry(addr).or_insert(0) += 1;
+    }
+
+    pub fn branch(&mut self, addr: usize, taken: bool) {
+        let entry = self.branches.entry(addr).or_insert((0, 0));
+        if taken {
+            entry.0 += 1;
+        } else {
+            entry.1 += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &Coverage) {
+        for (addr, count) in &other.hits {
+            *self.hits.entry(*addr).or_insert(0) += count;
+        }
+        for (addr, (taken, not_taken)) in &other.branches {
+            let entry = self.branches.entry(*addr).or_insert((0, 0));
+            entry.0 += taken;
+            entry.1 += not_taken;
+        }
+    }
+
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut coverage = Self::new();
+
+        for (i, line) in input.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || format!("Line {}: Invalid coverage data '{}'", i + 1, line);
+            let number = |s: &str| s.parse::<u64>().map_err(|_| invalid());
+            let address = |s: &str| {
+                s.strip_prefix("0x")
+                    .and_then(|s| usize::from_str_radix(s, 16).ok())
+                    .ok_or_else(invalid)
+            };
+
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            match fields.as_slice() {
+                ["HIT", addr, count] => {
+                    *coverage.hits.entry(address(addr)?).or_insert(0) += number(count)?;
+                }
+                ["BRANCH", addr, taken, not_taken] => {
+                    let entry = coverage.branches.entry(address(addr)?).or_insert((0, 0));
+                    entry.0 += number(taken)?;
+                    entry.1 += number(not_taken)?;
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(coverage)
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut output = String::from("; twn coverage data\n");
+
+        for (addr, count) in &self.hits {
+            writeln!(output, "HIT 0x{:04X} {}", addr, count).unwrap();
+        }
+        for (addr, (taken, not_taken)) in &self.branches {
+            writeln!(output, "BRANCH 0x{:04X} {} {}", addr, taken, not_taken).unwrap();
+        }
+
+        output
+    }
+
+    // ソース行ごとの集計
+    // program はマジックナンバーを除いたバイトコード (分岐命令の位置を調べるため)
+    pub fn lines(&self, debug: &DebugInfo, program: &[u8]) -> BTreeMap<usize, LineCoverage> {
+        let mut lines: BTreeMap<usize, LineCoverage> = BTreeMap::new();
+
+        for (addr, line) in &debug.lines {
+            let entry = lines.entry(*line).or_default();
+            entry.hits = entry.hits.max(self.hits.get(addr).copied().unwrap_or(0));
+
+            let is_branch = program
+                .get(*addr)
+                .and_then(|token| OpCode::from_u8(*token))
+                .is_some_and(is_branch);
+            if is_branch {
+                entry
+                    .branches
+                    .push((*addr, self.branches.get(addr).copied()));
+            }
+        }
+
+        lines
+    }
+
+    pub fn summary(&self, debug: &DebugInfo, program: &[u8]) -> String {
+        let lines = self.lines(debug, program);
+
+        let total_lines = lines.len();
+        let hit_lines = lines.values().filter(|l| l.hits > 0).count();
+        let total_branches = lines.values().map(|l| l.branches.len() * 2).sum::<usize>();
+        let hit_branches = lines.values().map(|l| l.branches_hit()).sum::<usize>();
+
+        let mut output = String::new();
+        writeln!(output, "=== COVERAGE ===").unwrap();
+        if let Some(source) = &debug.source {
+            writeln!(output, "Source  : {}", source).unwrap();
+        }
+        writeln!(
+            output,
+            "Lines   : {}/{} ({})",
+            hit_lines,
+            total_lines,
+            percent(hit_lines, total_lines)
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "Branches: {}/{} ({})",
+            hit_branches,
+            total_branches,
+            percent(hit_branches, total_branches)
+        )
+        .unwrap();
+
+        for (line, coverage) in &lines {
+            if coverage.hits == 0 {
+                writeln!(output, "  line {:>4}: not executed", line).unwrap();
+                continue;
+            }
+            for (addr, outcome) in &coverage.branches {
+                match outcome {
+                    Some((taken, 0)) if *taken > 0 => writeln!(
+                        output,
+                        "  line {:>4}: branch 0x{:04X} never fell through",
+                        line, addr
+                    )
+                    .unwrap(),
+                    Some((0, _)) | None => writeln!(
+                        output,
+                        "  line {:>4}: branch 0x{:04X} never taken",
+                        line, addr
+                    )
+                    .unwrap(),
+                    _ => {}
+                }
+            }
+        }
+        writeln!(output, "================").unwrap();
+
+        output
+    }
+
+    pub fn lcov(&self, debug: &DebugInfo, program: &[u8]) -> String {
+        let lines = self.lines(debug, program);
+
+        let mut output = String::new();
+        writeln!(output, "TN:").unwrap();
+        writeln!(output, "SF:{}", debug.source.as_deref().unwrap_or("")).unwrap();
+
+        let mut branch_found = 0;
+        let mut branch_hit = 0;
+        for (line, coverage) in &lines {
+            for (addr, outcome) in &coverage.branches {
+                let (taken, not_taken) = match outcome {
+                    Some((taken, not_taken)) => (taken.to_string(), not_taken.to_string()),
+                    None => ("-".to_string(), "-".to_string()),
+                };
+                writeln!(output, "BRDA:{},{},0,{}", line, addr, taken).unwrap();
+                writeln!(output, "BRDA:{},{},1,{}", line, addr, not_taken).unwrap();
+            }
+            branch_found += coverage.branches.len() * 2;
+            branch_hit += coverage.branches_hit();
+        }
+        writeln!(output, "BRF:{}", branch_found).unwrap();
+        writeln!(output, "BRH:{}", branch_hit).unwrap();
+
+        for (line, coverage) in &lines {
+            writeln!(output, "DA:{},{}", line, coverage.hits).unwrap();
+        }
+        writeln!(output, "LF:{}", lines.len()).unwrap();
+        writeln!(
+            output,
+            "LH:{}",
+            lines.values().filter(|l| l.hits > 0).count()
+        )
+        .unwrap();
+        writeln!(output, "end_of_record").unwrap();
+
+        output
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineCoverage {
+    pub hits: u64,
+    // (分岐命令のアドレス, 実行されていれば (taken, not taken))
+    pub branches: Vec<(usize, Option<(u64, u64)>)>,
+}
+impl LineCoverage {
+    pub fn branches_hit(&self) -> usize {
+        self.branches
+            .iter()
+            .map(|(_, outcome)| match outcome {
+                Some((taken, not_taken)) => (*taken > 0) as usize + (*not_taken > 0) as usize,
+                None => 0,
+            })
+            .sum()
+    }
+}
+
+pub fn is_branch(opcode: OpCode) -> bool {
+    matches!(
+        opcode,
+        OpCode::Jz
+            | OpCode::Jnz
+            | OpCode::Jeq
+            | OpCode::Jlt
+            | OpCode::Jgt
+            | OpCode::Jc
+            | OpCode::Jnc
+            | OpCode::Jo
+    )
+}
+
+fn percent(hit: usize, total: usize) -> String {
+    if total == 0 {
+        return "-".to_string();
+    }
+
+    format!("{:.1}%", hit as f64 * 100.0 / total as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_and_roundtrip() {
+        let mut a = Coverage::new();
+        a.hit(0x00);
+        a.branch(0x02, true);
+
+        let mut b = Coverage::new();
+        b.hit(0x00);
+        b.branch(0x02, false);
+
+        a.merge(&b);
+        assert_eq!(a.hits.get(&0x00), Some(&2));
+        assert_eq!(a.branches.get(&0x02), Some(&(1, 1)));
+        assert_eq!(Coverage::parse(&a.serialize()).unwrap(), a);
+    }
+
+    #[test]
+    fn test_lcov() {
+        // (line 1) PUSH 0, (line 2) JZ 0x05, (line 3) FIN, (line 4) FIN
+        let program = vec![
+            OpCode::Push as u8,
+            0,
+            OpCode::Jz as u8,
+            0x05,
+            OpCode::Fin as u8,
+            OpCode::Fin as u8,
+        ];
+        let debug = DebugInfo::new(vec![], vec![(0, 1), (2, 2), (4, 3), (5, 4)]);
+
+        let mut coverage = Coverage::new();
+        coverage.hit(0);
+        coverage.hit(2);
+        coverage.branch(2, true);
+        coverage.hit(5);
+
+        let lcov = coverage.lcov(&debug, &program);
+        assert!(lcov.contains("BRDA:2,2,0,1\n"));
+        assert!(lcov.contains("BRDA:2,2,1,0\n"));
+        assert!(lcov.contains("DA:3,0\n"));
+        assert!(lcov.contains("LH:3\n"));
+        assert!(lcov.contains("BRH:1\n"));
+    }
+}
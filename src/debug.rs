@@ -0,0 +1,151 @@
+use std::fmt::Write;
+
+// twnc が .twnd と並べて出力するデバッグ情報 (.twndbg)
+//
+// SOURCE <path>
+// LABEL <addr> <name>
+// LINE <addr> <line>
+//
+// アドレスはマジックナンバーを除いたバイトコード先頭からのオフセット (= VM の pc)
+// LINE は各命令の先頭アドレスとソースの行番号の対応
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub source: Option<String>,
+    pub symbols: Vec<(usize, String)>,
+    pub lines: Vec<(usize, usize)>,
+}
+impl DebugInfo {
+    pub fn new(mut symbols: Vec<(usize, String)>, mut lines: Vec<(usize, usize)>) -> Self {
+        symbols.sort();
+        lines.sort();
+
+        Self {
+            source: None,
+            symbols,
+            lines,
+        }
+    }
+
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut source: Option<String> = None;
+        let mut symbols: Vec<(usize, String)> = Vec::new();
+        let mut lines: Vec<(usize, usize)> = Vec::new();
+
+        for (i, line) in input.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(path) = line.strip_prefix("SOURCE ") {
+                source = Some(path.trim().to_string());
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            match fields.as_slice() {
+                ["LABEL", addr, name] => {
+                    symbols.push((parse_address(addr, i + 1)?, name.to_string()));
+                }
+                ["LINE", addr, number] => {
+                    let number = number
+                        .parse::<usize>()
+                        .map_err(|_| format!("Line {}: Invalid line number '{}'", i + 1, number))?;
+                    lines.push((parse_address(addr, i + 1)?, number));
+                }
+                _ => return Err(format!("Line {}: Invalid debug info '{}'", i + 1, line)),
+            }
+        }
+
+        let mut info = Self::new(symbols, lines);
+        info.source = source;
+
+        Ok(info)
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut output = String::from("; twn debug info\n");
+
+        if let Some(source) = &self.source {
+            writeln!(output, "SOURCE {}", source).unwrap();
+        }
+        for (addr, name) in &self.symbols {
+            writeln!(output, "LABEL 0x{:04X} {}", addr, name).unwrap();
+        }
+        for (addr, line) in &self.lines {
+            writeln!(output, "LINE 0x{:04X} {}", addr, line).unwrap();
+        }
+
+        output
+    }
+
+    // addr を含む命令のソース行
+    pub fn line(&self, addr: usize) -> Option<usize> {
+        let idx = self.lines.partition_point(|(a, _)| *a <= addr);
+        if idx == 0 {
+            return None;
+        }
+
+        Some(self.lines[idx - 1].1)
+    }
+
+    // addr を含むラベル (addr 以下で最も近いもの) とそこからのオフセットを返す
+    pub fn symbolize(&self, addr: usize) -> Option<(&str, usize)> {
+        let idx = self.symbols.partition_point(|(a, _)| *a <= addr);
+        if idx == 0 {
+            return None;
+        }
+
+        let (base, name) = &self.symbols[idx - 1];
+        Some((name.as_str(), addr - base))
+    }
+
+    // ラベル名のみ (見つからなければアドレス) を返す
+    pub fn frame_name(&self, addr: usize) -> String {
+        match self.symbolize(addr) {
+            Some((name, _)) => name.to_string(),
+            None => format!("0x{:04X}", addr),
+        }
+    }
+}
+
+fn parse_address(token: &str, line: usize) -> Result<usize, String> {
+    let digits = token
+        .strip_prefix("0x")
+        .or_else(|| token.strip_prefix("0X"))
+        .ok_or_else(|| format!("Line {}: Invalid address '{}'", line, token))?;
+
+    usize::from_str_radix(digits, 16)
+        .map_err(|_| format!("Line {}: Invalid address '{}'", line, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut info = DebugInfo::new(
+            vec![(0x10, "LOOP".to_string()), (0, "MAIN".to_string())],
+            vec![(0x00, 3), (0x02, 4), (0x10, 7)],
+        );
+        info.source = Some("examples/loop.twn".to_string());
+        let parsed = DebugInfo::parse(&info.serialize()).unwrap();
+
+        assert_eq!(parsed, info);
+    }
+
+    #[test]
+    fn test_symbolize() {
+        let info = DebugInfo::new(
+            vec![(0x04, "MAIN".to_string()), (0x10, "LOOP".to_string())],
+            vec![(0x04, 2), (0x10, 5)],
+        );
+
+        assert_eq!(info.symbolize(0x00), None);
+        assert_eq!(info.symbolize(0x04), Some(("MAIN", 0)));
+        assert_eq!(info.symbolize(0x12), Some(("LOOP", 2)));
+        assert_eq!(info.frame_name(0x02), "0x0002");
+        assert_eq!(info.line(0x11), Some(5));
+    }
+}
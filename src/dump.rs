@@ -0,0 +1,300 @@
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+
+use crate::cell::Cell;
+use crate::opcode::OpCode;
+use crate::vm::{VM, VmError};
+
+// DUMP システムコールの出力形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DumpFormat {
+    #[default]
+    Text, // 人が読むための形式 (メモリの16進グリッドと注釈付きのスタック)
+    Json, // 1行の JSON (ツールで読むための形式)
+}
+impl DumpFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+const ROW_SIZE: usize = 16;
+
+impl<R: Read, W: Write, D: Write, C: Cell> VM<R, W, D, C> {
+    pub fn dump(&self, format: DumpFormat) -> String {
+        match format {
+            DumpFormat::Text => self.dump_text(),
+            DumpFormat::Json => self.dump_json(),
+        }
+    }
+
+    // アドレスをラベル+オフセットで表す (デバッグ情報がなければ None)
+    fn symbolize(&self, addr: usize) -> Option<(&str, usize)> {
+        self.debug_info.as_ref()?.symbolize(addr)
+    }
+
+    fn annotate(&self, addr: usize) -> String {
+        match self.symbolize(addr) {
+            Some((name, 0)) => format!("0x{:04X} <{}>", addr, name),
+            Some((name, offset)) => format!("0x{:04X} <{}+{}>", addr, name, offset),
+            None => format!("0x{:04X}", addr),
+        }
+    }
+
+    fn dump_text(&self) -> String {
+        let mut output = String::from("=== VM STATE ===\n");
+
+        writeln!(output, "PC    : {}", self.annotate(self.pc)).unwrap();
+        writeln!(output, "FLAGS : {}", self.flags).unwrap();
+        writeln!(output, "FP    : {}", self.fp).unwrap();
+
+        // 値は16進 (セルの幅) と10進で表示する
+        // スタックは上 (最後に積んだもの) から並べる
+        let digits = C::WIDTH.bytes() * 2;
+        writeln!(output, "STACK : {} item(s)", self.stack.len()).unwrap();
+        for (i, val) in self.stack.iter().enumerate().rev() {
+            let top = if i + 1 == self.stack.len() {
+                "  <- top"
+            } else {
+                ""
+            };
+            writeln!(
+                output,
+                "  [{:3}] 0x{:0digits$X} {:3}{}",
+                i,
+                val.to_bits(),
+                val.value(),
+                top,
+                digits = digits
+            )
+            .unwrap();
+        }
+
+        // コールスタックは戻りアドレス (内側の呼び出しから並べる)
+        writeln!(output, "CALL  : {} frame(s)", self.call.len()).unwrap();
+        for (i, frame) in self.call.iter().enumerate().rev() {
+            writeln!(output, "  [{:3}] {}", i, self.annotate(frame.ret)).unwrap();
+        }
+
+        // 値の入っていないセルは --、すべて空の行は省略する
+        // ワイドモードではアドレスを4桁で表示する
+        let width = if self.header.wide { 4 } else { 2 };
+        writeln!(output, "MEMORY:").unwrap();
+        write!(output, "{:width$}", "", width = width + 5).unwrap();
+        for col in 0..ROW_SIZE {
+            write!(
+                output,
+                " {:>digits$}",
+                format!("{:02X}", col),
+                digits = digits
+            )
+            .unwrap();
+        }
+        output.push('\n');
+        for (row, cells) in self.memory.chunks(ROW_SIZE).enumerate() {
+            if cells.iter().all(|cell| cell.is_none()) {
+                continue;
+            }
+
+            write!(output, "  0x{:0width$X}:", row * ROW_SIZE, width = width).unwrap();
+            for cell in cells {
+                match cell {
+                    Some(val) => {
+                        write!(output, " {:0digits$X}", val.to_bits(), digits = digits).unwrap()
+                    }
+                    None => write!(output, " {}", "-".repeat(digits)).unwrap(),
+                }
+            }
+            output.push('\n');
+        }
+
+        output.push_str("================\n");
+        output
+    }
+
+    fn dump_json(&self) -> String {
+        let mut output = String::new();
+
+        write!(output, "{{\"pc\":{},\"fp\":{}", self.pc, self.fp).unwrap();
+        write!(
+            output,
+            ",\"flags\":{{\"zero\":{},\"carry\":{},\"overflow\":{},\"negative\":{}}}",
+            self.flags.zero, self.flags.carry, self.flags.overflow, self.flags.negative
+        )
+        .unwrap();
+
+        let stack = self.stack.iter().map(|val| val.value().to_string());
+        write!(output, ",\"stack\":[{}]", join(stack)).unwrap();
+
+        let call = self
+            .call
+            .iter()
+            .map(|frame| match self.symbolize(frame.ret) {
+                Some((name, offset)) => format!(
+                    "{{\"addr\":{},\"label\":{},\"offset\":{},\"fp\":{}}}",
+                    frame.ret,
+                    json_string(name),
+                    offset,
+                    frame.fp
+                ),
+                None => format!(
+                    "{{\"addr\":{},\"label\":null,\"offset\":null,\"fp\":{}}}",
+                    frame.ret, frame.fp
+                ),
+            });
+        write!(output, ",\"call\":[{}]", join(call)).unwrap();
+
+        let memory = self.memory.iter().map(|cell| match cell {
+            Some(val) => val.value().to_string(),
+            None => "null".to_string(),
+        });
+        write!(output, ",\"memory\":[{}]}}", join(memory)).unwrap();
+
+        output.push('\n');
+        output
+    }
+
+    // 実行時エラーの報告 (失敗した命令・実行前のスタックトップ・コールスタック)
+    // コールスタックは失敗した命令から外側の呼び出しへ向かって並べる
+    pub fn backtrace(&self, error: &VmError) -> String {
+        let mut output = String::new();
+
+        writeln!(output, "Instruction: {}", self.disassemble(error.pc)).unwrap();
+        match error.stack_top {
+            Some(val) => writeln!(
+                output,
+                "Stack top  : 0x{:0digits$X} {} ({} item(s))",
+                C::wrap(val).to_bits(),
+                val,
+                error.depth,
+                digits = C::WIDTH.bytes() * 2
+            )
+            .unwrap(),
+            None => writeln!(output, "Stack top  : (empty)").unwrap(),
+        }
+
+        writeln!(output, "Backtrace (innermost first):").unwrap();
+        for (i, addr) in self.frames(error.pc).into_iter().enumerate() {
+            match self.line(addr) {
+                Some(line) => {
+                    writeln!(output, "  #{} {} (line {})", i, self.annotate(addr), line).unwrap()
+                }
+                None => writeln!(output, "  #{} {}", i, self.annotate(addr)).unwrap(),
+            }
+        }
+
+        output
+    }
+
+    // 実行時エラーを1行の JSON にする (twnvm --error-format json)
+    pub fn error_json(&self, error: &VmError) -> String {
+        let mut output = String::new();
+
+        write!(
+            output,
+            "{{\"error\":\"runtime\",\"kind\":{},\"message\":{},\"exit_code\":{}",
+            json_string(error.kind.name()),
+            json_string(&error.kind.to_string()),
+            error.kind.exit_code()
+        )
+        .unwrap();
+        let opcode = match error.opcode {
+            Some(opcode) => json_string(opcode.name()),
+            None => "null".to_string(),
+        };
+        write!(
+            output,
+            ",\"pc\":{},\"opcode\":{},\"depth\":{},\"call_depth\":{}",
+            error.pc, opcode, error.depth, error.call_depth
+        )
+        .unwrap();
+
+        let top = match error.stack_top {
+            Some(val) => val.to_string(),
+            None => "null".to_string(),
+        };
+        write!(output, ",\"stack_top\":{}", top).unwrap();
+
+        let frames = self.frames(error.pc).into_iter().map(|addr| {
+            let (label, offset) = match self.symbolize(addr) {
+                Some((name, offset)) => (json_string(name), offset.to_string()),
+                None => ("null".to_string(), "null".to_string()),
+            };
+            let line = match self.line(addr) {
+                Some(line) => line.to_string(),
+                None => "null".to_string(),
+            };
+            format!(
+                "{{\"addr\":{},\"label\":{},\"offset\":{},\"line\":{}}}",
+                addr, label, offset, line
+            )
+        });
+        write!(output, ",\"backtrace\":[{}]}}", join(frames)).unwrap();
+
+        output.push('\n');
+        output
+    }
+
+    // 失敗した命令と、呼び出し元の CALL / CALLS 命令を内側から並べる
+    fn frames(&self, pc: usize) -> Vec<usize> {
+        std::iter::once(pc)
+            .chain(self.call.iter().rev().map(|frame| frame.site))
+            .collect()
+    }
+
+    fn line(&self, addr: usize) -> Option<usize> {
+        self.debug_info.as_ref()?.line(addr)
+    }
+
+    // addr の1命令を逆アセンブルする
+    fn disassemble(&self, addr: usize) -> String {
+        let Some(&byte) = self.tokens.get(addr) else {
+            return "(end of program)".to_string();
+        };
+        let Some(opcode) = OpCode::from_u8(byte) else {
+            return format!("(invalid opcode 0x{:02X})", byte);
+        };
+
+        let len = opcode.operand_len(self.header);
+        if len == 0 {
+            return opcode.name().to_string();
+        }
+        let bytes = self.tokens.iter().skip(addr + 1).take(len);
+        if bytes.len() < len {
+            return format!("{} (truncated)", opcode.name());
+        }
+
+        // 即値はリトルエンディアン
+        let bits = bytes.rev().fold(0u64, |acc, b| acc << 8 | *b as u64);
+        let operand = match opcode {
+            OpCode::LoadL | OpCode::StoreL => (bits as i8).to_string(),
+            _ if opcode.is_jump() => self.annotate(bits as usize),
+            _ if opcode.has_address_operand() => format!("0x{:02X}", bits),
+            _ if opcode.has_cell_operand() => C::from_bits(bits).value().to_string(),
+            _ => bits.to_string(),
+        };
+        format!("{} {}", opcode.name(), operand)
+    }
+}
+
+fn join(items: impl Iterator<Item = String>) -> String {
+    items.collect::<Vec<String>>().join(",")
+}
+
+pub fn json_string(text: &str) -> String {
+    let mut output = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(output, "\\u{:04x}", c as u32).unwrap(),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
@@ -0,0 +1,107 @@
+// 算術演算の結果を表すフラグレジスタ
+//
+// zero     : 結果が 0
+// carry    : 符号なしとして桁あふれした (減算では借りが出た)
+// overflow : セルと同じ幅の符号付き整数として範囲を超えた
+// negative : 結果の最上位ビットが 1
+//
+// carry と overflow は ArithMode で丸める前の本来の結果から、
+// zero と negative はスタックに積んだ値から決める
+use crate::cell::Cell;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags {
+    pub zero: bool,
+    pub carry: bool,
+    pub overflow: bool,
+    pub negative: bool,
+}
+
+const ZERO: u8 = 0b0001;
+const CARRY: u8 = 0b0010;
+const OVERFLOW: u8 = 0b0100;
+const NEGATIVE: u8 = 0b1000;
+
+impl Flags {
+    pub fn new<C: Cell>(result: C, carry: bool, overflow: bool) -> Self {
+        Self {
+            zero: result.to_bits() == 0,
+            carry,
+            overflow,
+            negative: result.signed() < 0,
+        }
+    }
+
+    // a + b + carry_in
+    pub fn add<C: Cell>(a: C, b: C, carry_in: bool, result: C) -> Self {
+        let unsigned = a.unsigned() + b.unsigned() + carry_in as i128;
+        let signed = a.signed() + b.signed() + carry_in as i128;
+
+        Self::new(
+            result,
+            !C::unsigned_range().contains(&unsigned),
+            !C::signed_range().contains(&signed),
+        )
+    }
+
+    // a - b - borrow_in
+    pub fn sub<C: Cell>(a: C, b: C, borrow_in: bool, result: C) -> Self {
+        let unsigned = a.unsigned() - b.unsigned() - borrow_in as i128;
+        let signed = a.signed() - b.signed() - borrow_in as i128;
+
+        Self::new(
+            result,
+            !C::unsigned_range().contains(&unsigned),
+            !C::signed_range().contains(&signed),
+        )
+    }
+
+    pub fn mul<C: Cell>(a: C, b: C, result: C) -> Self {
+        // 64bit の符号なし同士の積は i128 にも収まらないことがある
+        let unsigned = a.unsigned().checked_mul(b.unsigned());
+        let signed = a.signed() * b.signed();
+
+        Self::new(
+            result,
+            !unsigned.is_some_and(|n| C::unsigned_range().contains(&n)),
+            !C::signed_range().contains(&signed),
+        )
+    }
+
+    pub fn to_u8(self) -> u8 {
+        let mut bits = 0;
+        for (flag, bit) in [
+            (self.zero, ZERO),
+            (self.carry, CARRY),
+            (self.overflow, OVERFLOW),
+            (self.negative, NEGATIVE),
+        ] {
+            if flag {
+                bits |= bit;
+            }
+        }
+        bits
+    }
+
+    pub fn from_u8(bits: u8) -> Self {
+        Self {
+            zero: bits & ZERO != 0,
+            carry: bits & CARRY != 0,
+            overflow: bits & OVERFLOW != 0,
+            negative: bits & NEGATIVE != 0,
+        }
+    }
+}
+impl std::fmt::Display for Flags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (flag, name) in [
+            (self.zero, 'Z'),
+            (self.carry, 'C'),
+            (self.overflow, 'V'),
+            (self.negative, 'N'),
+        ] {
+            write!(f, "{}", if flag { name } else { '-' })?;
+        }
+        Ok(())
+    }
+}
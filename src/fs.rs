@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::vm::SysError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    Read = 0,   // 読み込み
+    Write = 1,  // 書き込み (作成・切り詰め)
+    Append = 2, // 追記 (作成)
+}
+impl OpenMode {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Self::Read),
+            1 => Some(Self::Write),
+            2 => Some(Self::Append),
+            _ => None,
+        }
+    }
+}
+
+pub trait FileHandle: Read + Write + Send {}
+impl<T: Read + Write + Send> FileHandle for T {}
+
+// VM が番号 (ハンドル) で管理する開いたファイル
+pub struct OpenFile {
+    pub handle: Box<dyn FileHandle>,
+    pub mode: OpenMode,
+}
+
+// OPEN システムコールが使うファイルシステム (VM ごと別のスレッドへ移せるよう Send に限る)
+pub trait FileSystem: Send {
+    fn open(&mut self, path: &str, mode: OpenMode) -> Result<Box<dyn FileHandle>, SysError>;
+}
+
+// メモリ上のファイルシステム (テスト用)
+// clone したものは同じファイルを共有するので、VM の外から中身を確認できる
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFileSystem {
+    pub files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    pub writable: bool,
+}
+impl MemoryFileSystem {
+    pub fn new(writable: bool) -> Self {
+        Self {
+            files: Arc::default(),
+            writable,
+        }
+    }
+
+    pub fn insert(&self, path: &str, content: &[u8]) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), content.to_vec());
+    }
+
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().get(path).cloned()
+    }
+}
+impl FileSystem for MemoryFileSystem {
+    fn open(&mut self, path: &str, mode: OpenMode) -> Result<Box<dyn FileHandle>, SysError> {
+        if mode != OpenMode::Read && !self.writable {
+            return Err(SysError::PermissionDenied(path.to_string()));
+        }
+
+        let mut files = self.files.lock().unwrap();
+        match mode {
+            OpenMode::Read => {
+                if !files.contains_key(path) {
+                    return Err(SysError::FileNotFound(path.to_string()));
+                }
+            }
+            OpenMode::Write => {
+                files.insert(path.to_string(), Vec::new());
+            }
+            OpenMode::Append => {
+                files.entry(path.to_string()).or_default();
+            }
+        }
+
+        Ok(Box::new(MemoryFile {
+            files: Arc::clone(&self.files),
+            path: path.to_string(),
+            pos: 0,
+        }))
+    }
+}
+
+struct MemoryFile {
+    files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    path: String,
+    pos: usize,
+}
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let files = self.files.lock().unwrap();
+        let content = files.get(&self.path).map(|c| c.as_slice()).unwrap_or(&[]);
+
+        let rest = content.get(self.pos..).unwrap_or(&[]);
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.files
+            .lock()
+            .unwrap()
+            .entry(self.path.clone())
+            .or_default()
+            .extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// root 以下に閉じ込めたホストのファイルシステム
+// 絶対パスや .. を含むパス、シンボリックリンクで root の外を指すパスは拒否する
+// 書き込みは writable の場合のみ許可する
+pub struct HostFileSystem {
+    pub root: PathBuf,
+    pub writable: bool,
+}
+impl HostFileSystem {
+    pub fn new(root: impl AsRef<Path>, writable: bool) -> std::io::Result<Self> {
+        Ok(Self {
+            root: root.as_ref().canonicalize()?,
+            writable,
+        })
+    }
+
+    fn resolve(&self, path: &str) -> Result<PathBuf, SysError> {
+        let relative = Path::new(path);
+        let escapes = relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+        if escapes || path.is_empty() {
+            return Err(SysError::InvalidPath(path.to_string()));
+        }
+
+        let full = self.root.join(relative);
+
+        // 既にあるファイルならそのもの、新規作成なら親ディレクトリの実体が root の中にあるか確かめる
+        // 行き先の無いシンボリックリンクは作成時にリンク先 (root の外かもしれない) を作ってしまうので拒否する
+        let real = match full.canonicalize() {
+            Ok(real) => real,
+            Err(_) if full.symlink_metadata().is_ok() => {
+                return Err(SysError::InvalidPath(path.to_string()));
+            }
+            Err(_) => match full.parent().map(|p| p.canonicalize()) {
+                Some(Ok(parent)) => parent,
+                _ => return Err(SysError::FileNotFound(path.to_string())),
+            },
+        };
+        if !real.starts_with(&self.root) {
+            return Err(SysError::InvalidPath(path.to_string()));
+        }
+
+        Ok(full)
+    }
+}
+impl FileSystem for HostFileSystem {
+    fn open(&mut self, path: &str, mode: OpenMode) -> Result<Box<dyn FileHandle>, SysError> {
+        if mode != OpenMode::Read && !self.writable {
+            return Err(SysError::PermissionDenied(path.to_string()));
+        }
+
+        let full = self.resolve(path)?;
+        let mut options = std::fs::OpenOptions::new();
+        match mode {
+            OpenMode::Read => options.read(true),
+            OpenMode::Write => options.write(true).create(true).truncate(true),
+            OpenMode::Append => options.append(true).create(true),
+        };
+
+        match options.open(&full) {
+            Ok(file) => Ok(Box::new(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(SysError::FileNotFound(path.to_string()))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                Err(SysError::PermissionDenied(path.to_string()))
+            }
+            Err(e) => Err(SysError::Io(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_rejects_escape() {
+        let root = std::env::temp_dir().join(format!("twn-fs-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        let mut fs = HostFileSystem::new(&root, false).unwrap();
+        for path in ["../etc/passwd", "/etc/passwd", "a/../../b", ""] {
+            match fs.open(path, OpenMode::Read) {
+                Err(SysError::InvalidPath(_)) => (),
+                _ => panic!("Expected InvalidPath for {:?}", path),
+            }
+        }
+        match fs.open("new.txt", OpenMode::Write) {
+            Err(SysError::PermissionDenied(_)) => (),
+            _ => panic!("Expected PermissionDenied"),
+        }
+
+        let mut fs = HostFileSystem::new(&root, true).unwrap();
+        fs.open("new.txt", OpenMode::Write)
+            .unwrap()
+            .write_all(b"twn")
+            .unwrap();
+        assert_eq!(std::fs::read(root.join("new.txt")).unwrap(), b"twn");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_host_rejects_symlink_escape() {
+        let base = std::env::temp_dir().join(format!("twn-fs-link-{}", std::process::id()));
+        let root = base.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::os::unix::fs::symlink(base.join("outside.txt"), root.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(&base, root.join("up")).unwrap();
+
+        let mut fs = HostFileSystem::new(&root, true).unwrap();
+        for path in ["dangling", "up/outside.txt"] {
+            match fs.open(path, OpenMode::Write) {
+                Err(SysError::InvalidPath(_)) => (),
+                _ => panic!("Expected InvalidPath for {:?}", path),
+            }
+        }
+        assert!(!base.join("outside.txt").exists());
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
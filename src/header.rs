@@ -0,0 +1,75 @@
+// バイトコード (.twnd) の先頭
+//
+// "\0TWN"           : 従来の形式 (アドレスは 8bit)
+// "\0TWE" | flags   : 拡張形式
+//
+// flags の bit0 はワイドアドレスモード
+// (ジャンプ先と即値のメモリアドレスが 16bit リトルエンディアン、メモリは 64KiB)
+// bit1-2 はセルの型 (0: u8, 1: u16, 2: u32, 3: i64)
+use crate::cell::CellWidth;
+
+pub const MAGIC: [u8; 4] = [0x00, b'T', b'W', b'N'];
+pub const MAGIC_EXTENDED: [u8; 4] = [0x00, b'T', b'W', b'E'];
+
+const FLAG_WIDE: u8 = 0b0000_0001;
+const CELL_SHIFT: u8 = 1;
+const CELL_MASK: u8 = 0b0000_0110;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Header {
+    pub wide: bool,
+    pub cell: CellWidth,
+}
+impl Header {
+    // 先頭を読み、ヘッダとその長さを返す
+    pub fn parse(bytes: &[u8]) -> Option<(Self, usize)> {
+        if bytes.starts_with(&MAGIC) {
+            return Some((Self::default(), MAGIC.len()));
+        }
+
+        if bytes.starts_with(&MAGIC_EXTENDED) {
+            let flags = *bytes.get(MAGIC_EXTENDED.len())?;
+            let header = Self::from_flags(flags)?;
+            return Some((header, MAGIC_EXTENDED.len() + 1));
+        }
+
+        None
+    }
+
+    pub fn from_flags(flags: u8) -> Option<Self> {
+        if flags & !(FLAG_WIDE | CELL_MASK) != 0 {
+            return None;
+        }
+
+        Some(Self {
+            wide: flags & FLAG_WIDE != 0,
+            cell: CellWidth::from_code((flags & CELL_MASK) >> CELL_SHIFT)?,
+        })
+    }
+
+    pub fn flags(self) -> u8 {
+        let wide = if self.wide { FLAG_WIDE } else { 0 };
+        wide | self.cell.code() << CELL_SHIFT
+    }
+
+    // 拡張が何もなければ従来の形式で書き出す
+    pub fn to_bytes(self) -> Vec<u8> {
+        if self == Self::default() {
+            return MAGIC.to_vec();
+        }
+
+        let mut bytes = MAGIC_EXTENDED.to_vec();
+        bytes.push(self.flags());
+        bytes
+    }
+
+    // 即値のアドレスのバイト数
+    pub fn address_len(self) -> usize {
+        if self.wide { 2 } else { 1 }
+    }
+
+    // PUSH などの即値の値のバイト数
+    pub fn cell_len(self) -> usize {
+        self.cell.bytes()
+    }
+}
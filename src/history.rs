@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+
+use crate::flags::Flags;
+use crate::vm::Frame;
+
+// 1命令の実行でVMに加えられた変更 (巻き戻し用)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change<C = u8> {
+    Push,                    // スタックに積んだ
+    Pop(C),                  // スタックから取り出した値
+    Store(usize, Option<C>), // メモリに書き込んだアドレスと書き込む前の値
+    PushCall,                // コールスタックに積んだ
+    PopCall(Frame),          // コールスタックから取り出したフレーム
+    Rng(u64),                // 乱数生成器の以前の状態
+    Flags(Flags),            // フラグレジスタの以前の状態
+    Fp(usize),               // フレームポインタの以前の値
+    Local(usize, C),         // STOREL で書き換えたスタックの位置と以前の値
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step<C = u8> {
+    pub pc: usize,
+    pub halted: bool,
+    pub exit_code: u8,
+    pub changes: Vec<Change<C>>,
+}
+impl<C> Step<C> {
+    pub fn writes(&self, addr: usize) -> bool {
+        self.changes
+            .iter()
+            .any(|change| matches!(change, Change::Store(dst, _) if *dst == addr))
+    }
+}
+
+// 直近 capacity 命令分の変更履歴 (リングバッファ)
+// 入出力 (READ/PRINT など) は巻き戻せない
+#[derive(Debug, Clone)]
+pub struct History<C = u8> {
+    pub capacity: usize,
+    pub steps: VecDeque<Step<C>>,
+}
+impl<C> History<C> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            steps: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn begin(&mut self, pc: usize, halted: bool, exit_code: u8) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+
+        self.steps.push_back(Step {
+            pc,
+            halted,
+            exit_code,
+            changes: Vec::new(),
+        });
+    }
+
+    pub fn record(&mut self, change: Change<C>) {
+        if let Some(step) = self.steps.back_mut() {
+            step.changes.push(change);
+        }
+    }
+
+    // 実行した命令のアドレス (古い順)
+    pub fn pcs(&self) -> impl Iterator<Item = usize> + '_ {
+        self.steps.iter().map(|step| step.pc)
+    }
+
+    // 履歴の中で最後に addr へ書き込んだ命令が何命令前か
+    pub fn last_write(&self, addr: usize) -> Option<usize> {
+        self.steps.iter().rev().position(|step| step.writes(addr))
+    }
+}
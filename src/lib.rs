@@ -1,3 +1,17 @@
 pub mod assembler;
+pub mod cell;
+pub mod clock;
+pub mod coverage;
+pub mod debug;
+pub mod dump;
+pub mod flags;
+pub mod fs;
+pub mod header;
+pub mod history;
 pub mod opcode;
+pub mod profile;
+pub mod rng;
+pub mod snapshot;
+pub mod syscall;
+pub mod verify;
 pub mod vm;
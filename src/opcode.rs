@@ -5,6 +5,15 @@
  * 0x02: POP
  * 0x03: DUP
  * 0x04: SWAP
+ * 0x05: OVER
+ * 0x06: ROT
+ * 0x07: -ROT
+ * 0x08: NIP
+ * 0x09: TUCK
+ * 0x0A: PICK
+ * 0x0B: ROLL
+ * 0x0C: DROP
+ * 0x0D: DEPTH
  * 0x10: ADD
  * 0x11: SUB
  * 0x12: MUL
@@ -23,15 +32,52 @@
  * 0x1F: GE
  * 0x20: JZ
  * 0x21: JMZ
+ * 0x22: JC
+ * 0x23: JNC
+ * 0x24: JO
+ * 0x25: JNZ
+ * 0x26: JEQ
+ * 0x27: JLT
+ * 0x28: JGT
+ * 0x29: JMPS
  * 0x30: STORE
  * 0x31: LOAD
  * 0x32: STOREI
  * 0x33: LOADI
+ * 0x34: LOADL
+ * 0x35: STOREL
  * 0x40: CALL
  * 0x41: RET
+ * 0x42: CALLS
+ * 0x43: ENTER
+ * 0x44: LEAVE
+ * 0x50: AND
+ * 0x51: OR
+ * 0x52: XOR
+ * 0x53: NOT
+ * 0x54: SHL
+ * 0x55: SHR
+ * 0x56: ANDI
+ * 0x57: ORI
+ * 0x58: XORI
+ * 0x59: SHLI
+ * 0x5A: SHRI
+ * 0x60: SADD
+ * 0x61: SSUB
+ * 0x62: SMUL
+ * 0x63: SDIV
+ * 0x64: SMOD
+ * 0x65: SLT
+ * 0x66: SGT
+ * 0x67: NEG
+ * 0x68: ADC
+ * 0x69: SBC
+ * 0x6A: CLC
  * 0xFF: FIN
  */
 
+use crate::header::Header;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum OpCode {
@@ -40,6 +86,15 @@ pub enum OpCode {
     Pop = 0x02,
     Dup = 0x03,
     Swap = 0x04,
+    Over = 0x05,
+    Rot = 0x06,
+    NRot = 0x07,
+    Nip = 0x08,
+    Tuck = 0x09,
+    Pick = 0x0A,
+    Roll = 0x0B,
+    DropN = 0x0C,
+    Depth = 0x0D,
     Add = 0x10,
     Sub = 0x11,
     Mul = 0x12,
@@ -58,12 +113,47 @@ pub enum OpCode {
     Ge = 0x1F,
     Jz = 0x20,
     Jmz = 0x21,
+    Jc = 0x22,
+    Jnc = 0x23,
+    Jo = 0x24,
+    Jnz = 0x25,
+    Jeq = 0x26,
+    Jlt = 0x27,
+    Jgt = 0x28,
+    JmpS = 0x29,
     Store = 0x30,
     Load = 0x31,
     StoreI = 0x32,
     LoadI = 0x33,
+    LoadL = 0x34,
+    StoreL = 0x35,
     Call = 0x40,
     Ret = 0x41,
+    CallS = 0x42,
+    Enter = 0x43,
+    Leave = 0x44,
+    And = 0x50,
+    Or = 0x51,
+    Xor = 0x52,
+    Not = 0x53,
+    Shl = 0x54,
+    Shr = 0x55,
+    AndI = 0x56,
+    OrI = 0x57,
+    XorI = 0x58,
+    ShlI = 0x59,
+    ShrI = 0x5A,
+    SAdd = 0x60,
+    SSub = 0x61,
+    SMul = 0x62,
+    SDiv = 0x63,
+    SMod = 0x64,
+    SLt = 0x65,
+    SGt = 0x66,
+    Neg = 0x67,
+    Adc = 0x68,
+    Sbc = 0x69,
+    Clc = 0x6A,
     Fin = 0xFF,
 }
 
@@ -75,6 +165,15 @@ impl OpCode {
             0x02 => Some(Self::Pop),
             0x03 => Some(Self::Dup),
             0x04 => Some(Self::Swap),
+            0x05 => Some(Self::Over),
+            0x06 => Some(Self::Rot),
+            0x07 => Some(Self::NRot),
+            0x08 => Some(Self::Nip),
+            0x09 => Some(Self::Tuck),
+            0x0A => Some(Self::Pick),
+            0x0B => Some(Self::Roll),
+            0x0C => Some(Self::DropN),
+            0x0D => Some(Self::Depth),
             0x10 => Some(Self::Add),
             0x11 => Some(Self::Sub),
             0x12 => Some(Self::Mul),
@@ -93,17 +192,53 @@ impl OpCode {
             0x1F => Some(Self::Ge),
             0x20 => Some(Self::Jz),
             0x21 => Some(Self::Jmz),
+            0x22 => Some(Self::Jc),
+            0x23 => Some(Self::Jnc),
+            0x24 => Some(Self::Jo),
+            0x25 => Some(Self::Jnz),
+            0x26 => Some(Self::Jeq),
+            0x27 => Some(Self::Jlt),
+            0x28 => Some(Self::Jgt),
+            0x29 => Some(Self::JmpS),
             0x30 => Some(Self::Store),
             0x31 => Some(Self::Load),
             0x32 => Some(Self::StoreI),
             0x33 => Some(Self::LoadI),
+            0x34 => Some(Self::LoadL),
+            0x35 => Some(Self::StoreL),
             0x40 => Some(Self::Call),
             0x41 => Some(Self::Ret),
+            0x42 => Some(Self::CallS),
+            0x43 => Some(Self::Enter),
+            0x44 => Some(Self::Leave),
+            0x50 => Some(Self::And),
+            0x51 => Some(Self::Or),
+            0x52 => Some(Self::Xor),
+            0x53 => Some(Self::Not),
+            0x54 => Some(Self::Shl),
+            0x55 => Some(Self::Shr),
+            0x56 => Some(Self::AndI),
+            0x57 => Some(Self::OrI),
+            0x58 => Some(Self::XorI),
+            0x59 => Some(Self::ShlI),
+            0x5A => Some(Self::ShrI),
+            0x60 => Some(Self::SAdd),
+            0x61 => Some(Self::SSub),
+            0x62 => Some(Self::SMul),
+            0x63 => Some(Self::SDiv),
+            0x64 => Some(Self::SMod),
+            0x65 => Some(Self::SLt),
+            0x66 => Some(Self::SGt),
+            0x67 => Some(Self::Neg),
+            0x68 => Some(Self::Adc),
+            0x69 => Some(Self::Sbc),
+            0x6A => Some(Self::Clc),
             0xFF => Some(Self::Fin),
             _ => None,
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<Self> {
         match s.to_uppercase().as_str() {
             "SYSCALL" => Some(Self::SysCall),
@@ -111,6 +246,15 @@ impl OpCode {
             "POP" => Some(Self::Pop),
             "DUP" => Some(Self::Dup),
             "SWAP" => Some(Self::Swap),
+            "OVER" => Some(Self::Over),
+            "ROT" => Some(Self::Rot),
+            "-ROT" => Some(Self::NRot),
+            "NIP" => Some(Self::Nip),
+            "TUCK" => Some(Self::Tuck),
+            "PICK" => Some(Self::Pick),
+            "ROLL" => Some(Self::Roll),
+            "DROP" => Some(Self::DropN),
+            "DEPTH" => Some(Self::Depth),
             "ADD" => Some(Self::Add),
             "SUB" => Some(Self::Sub),
             "MUL" => Some(Self::Mul),
@@ -129,14 +273,192 @@ impl OpCode {
             "GE" => Some(Self::Ge),
             "JZ" => Some(Self::Jz),
             "JMZ" => Some(Self::Jmz),
+            "JC" => Some(Self::Jc),
+            "JNC" => Some(Self::Jnc),
+            "JO" => Some(Self::Jo),
+            "JNZ" => Some(Self::Jnz),
+            "JEQ" => Some(Self::Jeq),
+            "JLT" => Some(Self::Jlt),
+            "JGT" => Some(Self::Jgt),
+            "JMPS" => Some(Self::JmpS),
+            // 比較演算は成立時に 0 を積むので、JT (真なら分岐) は JZ、JF は JNZ の別名
+            "JT" => Some(Self::Jz),
+            "JF" => Some(Self::Jnz),
             "STORE" => Some(Self::Store),
             "LOAD" => Some(Self::Load),
             "STOREI" => Some(Self::StoreI),
             "LOADI" => Some(Self::LoadI),
+            "LOADL" => Some(Self::LoadL),
+            "STOREL" => Some(Self::StoreL),
             "CALL" => Some(Self::Call),
             "RET" => Some(Self::Ret),
+            "CALLS" => Some(Self::CallS),
+            "ENTER" => Some(Self::Enter),
+            "LEAVE" => Some(Self::Leave),
+            "AND" => Some(Self::And),
+            "OR" => Some(Self::Or),
+            "XOR" => Some(Self::Xor),
+            "NOT" => Some(Self::Not),
+            "SHL" => Some(Self::Shl),
+            "SHR" => Some(Self::Shr),
+            "ANDI" => Some(Self::AndI),
+            "ORI" => Some(Self::OrI),
+            "XORI" => Some(Self::XorI),
+            "SHLI" => Some(Self::ShlI),
+            "SHRI" => Some(Self::ShrI),
+            "SADD" => Some(Self::SAdd),
+            "SSUB" => Some(Self::SSub),
+            "SMUL" => Some(Self::SMul),
+            "SDIV" => Some(Self::SDiv),
+            "SMOD" => Some(Self::SMod),
+            "SLT" => Some(Self::SLt),
+            "SGT" => Some(Self::SGt),
+            "NEG" => Some(Self::Neg),
+            "ADC" => Some(Self::Adc),
+            "SBC" => Some(Self::Sbc),
+            "CLC" => Some(Self::Clc),
             "FIN" => Some(Self::Fin),
             _ => None,
         }
     }
+
+    // ニーモニック (別名があるものは元の名前)
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::SysCall => "SYSCALL",
+            Self::Push => "PUSH",
+            Self::Pop => "POP",
+            Self::Dup => "DUP",
+            Self::Swap => "SWAP",
+            Self::Over => "OVER",
+            Self::Rot => "ROT",
+            Self::NRot => "-ROT",
+            Self::Nip => "NIP",
+            Self::Tuck => "TUCK",
+            Self::Pick => "PICK",
+            Self::Roll => "ROLL",
+            Self::DropN => "DROP",
+            Self::Depth => "DEPTH",
+            Self::Add => "ADD",
+            Self::Sub => "SUB",
+            Self::Mul => "MUL",
+            Self::Div => "DIV",
+            Self::Mod => "MOD",
+            Self::AddI => "ADDI",
+            Self::SubI => "SUBI",
+            Self::MulI => "MULI",
+            Self::DivI => "DIVI",
+            Self::ModI => "MODI",
+            Self::Eq => "EQ",
+            Self::Neq => "NEQ",
+            Self::Lt => "LT",
+            Self::Le => "LE",
+            Self::Gt => "GT",
+            Self::Ge => "GE",
+            Self::Jz => "JZ",
+            Self::Jmz => "JMZ",
+            Self::Jc => "JC",
+            Self::Jnc => "JNC",
+            Self::Jo => "JO",
+            Self::Jnz => "JNZ",
+            Self::Jeq => "JEQ",
+            Self::Jlt => "JLT",
+            Self::Jgt => "JGT",
+            Self::JmpS => "JMPS",
+            Self::Store => "STORE",
+            Self::Load => "LOAD",
+            Self::StoreI => "STOREI",
+            Self::LoadI => "LOADI",
+            Self::LoadL => "LOADL",
+            Self::StoreL => "STOREL",
+            Self::Call => "CALL",
+            Self::Ret => "RET",
+            Self::CallS => "CALLS",
+            Self::Enter => "ENTER",
+            Self::Leave => "LEAVE",
+            Self::And => "AND",
+            Self::Or => "OR",
+            Self::Xor => "XOR",
+            Self::Not => "NOT",
+            Self::Shl => "SHL",
+            Self::Shr => "SHR",
+            Self::AndI => "ANDI",
+            Self::OrI => "ORI",
+            Self::XorI => "XORI",
+            Self::ShlI => "SHLI",
+            Self::ShrI => "SHRI",
+            Self::SAdd => "SADD",
+            Self::SSub => "SSUB",
+            Self::SMul => "SMUL",
+            Self::SDiv => "SDIV",
+            Self::SMod => "SMOD",
+            Self::SLt => "SLT",
+            Self::SGt => "SGT",
+            Self::Neg => "NEG",
+            Self::Adc => "ADC",
+            Self::Sbc => "SBC",
+            Self::Clc => "CLC",
+            Self::Fin => "FIN",
+        }
+    }
+
+    // 命令に続く即値のバイト数 (アドレスと値の幅はヘッダで決まる)
+    pub fn operand_len(self, header: Header) -> usize {
+        if self.has_address_operand() {
+            return header.address_len();
+        }
+        if self.has_cell_operand() {
+            return header.cell_len();
+        }
+
+        match self {
+            Self::Pick
+            | Self::Roll
+            | Self::DropN
+            | Self::ShlI
+            | Self::ShrI
+            | Self::LoadL
+            | Self::StoreL
+            | Self::Enter => 1,
+            _ => 0,
+        }
+    }
+
+    // 即値がセルの値の命令
+    pub fn has_cell_operand(self) -> bool {
+        matches!(
+            self,
+            Self::Push
+                | Self::AddI
+                | Self::SubI
+                | Self::MulI
+                | Self::DivI
+                | Self::ModI
+                | Self::AndI
+                | Self::OrI
+                | Self::XorI
+        )
+    }
+
+    // 即値がアドレスの命令
+    pub fn has_address_operand(self) -> bool {
+        self.is_jump() || matches!(self, Self::StoreI | Self::LoadI)
+    }
+
+    // 即値のアドレスへ飛ぶ命令
+    pub fn is_jump(self) -> bool {
+        matches!(
+            self,
+            Self::Jz
+                | Self::Jmz
+                | Self::Jc
+                | Self::Jnc
+                | Self::Jo
+                | Self::Jnz
+                | Self::Jeq
+                | Self::Jlt
+                | Self::Jgt
+                | Self::Call
+        )
+    }
 }
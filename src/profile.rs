@@ -0,0 +1,49 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use crate::debug::DebugInfo;
+
+// コールスタックごとの実行命令数
+// キーはプログラムの先頭 (0 番地) に VM.call の呼び出し先アドレス列を続けたもの
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub samples: HashMap<Vec<usize>, u64>,
+}
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, call: &[usize]) {
+        let mut key = Vec::with_capacity(call.len() + 1);
+        key.push(0);
+        key.extend_from_slice(call);
+
+        *self.samples.entry(key).or_insert(0) += 1;
+    }
+
+    // flamegraph.pl / inferno が読める folded stack 形式
+    // MAIN;POPUP 12
+    pub fn folded(&self, debug: &DebugInfo) -> BTreeMap<String, u64> {
+        let mut folded: BTreeMap<String, u64> = BTreeMap::new();
+
+        for (stack, count) in &self.samples {
+            let frames = stack
+                .iter()
+                .map(|addr| debug.frame_name(*addr))
+                .collect::<Vec<String>>();
+
+            *folded.entry(frames.join(";")).or_insert(0) += count;
+        }
+
+        folded
+    }
+
+    pub fn write_folded<W: Write>(&self, debug: &DebugInfo, out: &mut W) -> std::io::Result<()> {
+        for (stack, count) in self.folded(debug) {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,357 @@
+use std::io::{Read, Write};
+
+use crate::cell::{Cell, CellWidth};
+use crate::flags::Flags;
+use crate::header::Header;
+use crate::rng::Rng;
+use crate::vm::{CALL_SIZE, Frame, MEMORY_SIZE, STACK_SIZE, VM, VmConfig, WIDE_MEMORY_SIZE};
+
+// VM の状態を保存したバイナリ (.twns)
+//
+// 数値はすべてリトルエンディアン、長さとアドレスは u32
+// "\0TWS" | version: u8 | header: u8 (.twnd のヘッダのフラグ) | pc | fp | halted: u8
+// | exit_code: u8 | rng: u64 | flags: u8 | stack: len, [cell] | memory: len, [(tag: u8, val: cell)]
+// | call: len, [(ret: u32, fp: u32, site: u32, entry: u32)] | program: len, [u8]
+//
+// cell はヘッダのセルの型の幅
+// memory の tag は 0 = 未初期化, 1 = 値あり
+const SNAPSHOT_MAGIC: [u8; 4] = [0x00, b'T', b'W', b'S'];
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    InvalidMagic,           // マジックナンバーが違う
+    UnsupportedVersion(u8), // 知らないバージョン
+    UnexpectedEof,          // 途中でファイルが終わった
+    InvalidMemoryTag(u8),   // memory のタグが 0/1 以外
+    InvalidHeader(u8),      // 知らないヘッダのフラグ
+    WrongCell(CellWidth),   // 読み込む VM とセルの型が違う
+    InvalidLength(usize),   // 長さが上限や残りのデータを超えている
+    TrailingData(usize),    // 末尾に余分なデータがある
+}
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidMagic => write!(f, "Invalid snapshot: Magic number not found"),
+            Self::UnsupportedVersion(v) => write!(f, "Unsupported snapshot version: {}", v),
+            Self::UnexpectedEof => write!(f, "Unexpected EOF in snapshot"),
+            Self::InvalidMemoryTag(tag) => write!(f, "Invalid memory tag: {:02X}", tag),
+            Self::InvalidHeader(flags) => write!(f, "Invalid header flags: {:02X}", flags),
+            Self::WrongCell(cell) => {
+                write!(f, "Snapshot uses {} cells, which the VM does not", cell)
+            }
+            Self::InvalidLength(len) => write!(f, "Invalid length in snapshot: {}", len),
+            Self::TrailingData(n) => write!(f, "Trailing data in snapshot: {} bytes", n),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() - self.pos < n {
+            return Err(SnapshotError::UnexpectedEof);
+        }
+
+        let slice = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn u32(&mut self) -> Result<usize, SnapshotError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    // 要素数 (max 以下で、item バイトの要素がその数だけ残っていること)
+    // 壊れたファイルで巨大な領域を確保しないよう、読む前に確かめる
+    fn len(&mut self, max: usize, item: usize) -> Result<usize, SnapshotError> {
+        let len = self.u32()?;
+        if max < len || self.bytes.len() - self.pos < len * item {
+            return Err(SnapshotError::InvalidLength(len));
+        }
+
+        Ok(len)
+    }
+
+    fn cell<C: Cell>(&mut self) -> Result<C, SnapshotError> {
+        let bytes = self.take(C::WIDTH.bytes())?;
+        let bits = bytes
+            .iter()
+            .rev()
+            .fold(0, |acc, byte| acc << 8 | *byte as u64);
+        Ok(C::from_bits(bits))
+    }
+
+    fn header(&mut self) -> Result<Header, SnapshotError> {
+        if self.take(SNAPSHOT_MAGIC.len()).ok() != Some(&SNAPSHOT_MAGIC[..]) {
+            return Err(SnapshotError::InvalidMagic);
+        }
+        let version = self.u8()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let flags = self.u8()?;
+        Header::from_flags(flags).ok_or(SnapshotError::InvalidHeader(flags))
+    }
+}
+
+// 復元する前にセルの型などを知るために、スナップショットのヘッダだけを読む
+pub fn snapshot_header(bytes: &[u8]) -> Result<Header, SnapshotError> {
+    Reader { bytes, pos: 0 }.header()
+}
+
+fn push_u32(output: &mut Vec<u8>, n: usize) {
+    output.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+fn push_cell<C: Cell>(output: &mut Vec<u8>, val: C) {
+    output.extend_from_slice(&val.to_bits().to_le_bytes()[..C::WIDTH.bytes()]);
+}
+
+impl<R: Read, W: Write, D: Write, C: Cell> VM<R, W, D, C> {
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut output: Vec<u8> = Vec::new();
+        output.extend_from_slice(&SNAPSHOT_MAGIC);
+        output.push(SNAPSHOT_VERSION);
+        output.push(self.header.flags());
+
+        push_u32(&mut output, self.pc);
+        push_u32(&mut output, self.fp);
+        output.push(self.halted as u8);
+        output.push(self.exit_code);
+        output.extend_from_slice(&self.rng.state.to_le_bytes());
+        output.push(self.flags.to_u8());
+
+        push_u32(&mut output, self.stack.len());
+        for val in &self.stack {
+            push_cell(&mut output, *val);
+        }
+
+        push_u32(&mut output, self.memory.len());
+        for cell in &self.memory {
+            match cell {
+                Some(val) => {
+                    output.push(1);
+                    push_cell(&mut output, *val);
+                }
+                None => {
+                    output.push(0);
+                    push_cell(&mut output, C::default());
+                }
+            }
+        }
+
+        push_u32(&mut output, self.call.len());
+        for frame in &self.call {
+            push_u32(&mut output, frame.ret);
+            push_u32(&mut output, frame.fp);
+            push_u32(&mut output, frame.site);
+            push_u32(&mut output, frame.entry);
+        }
+
+        push_u32(&mut output, self.tokens.len());
+        output.extend_from_slice(&self.tokens);
+
+        output
+    }
+
+    pub fn from_snapshot_with_debug_port(
+        bytes: &[u8],
+        in_port: R,
+        out_port: W,
+        debug_port: D,
+    ) -> Result<Self, SnapshotError> {
+        let mut reader = Reader { bytes, pos: 0 };
+
+        let header = reader.header()?;
+        if header.cell != C::WIDTH {
+            return Err(SnapshotError::WrongCell(header.cell));
+        }
+
+        let pc = reader.u32()?;
+        let fp = reader.u32()?;
+        let halted = reader.u8()? != 0;
+        let exit_code = reader.u8()?;
+        let rng = reader.u64()?;
+        let flags = reader.u8()?;
+
+        let cell = C::WIDTH.bytes();
+        let memory_size = if header.wide {
+            WIDE_MEMORY_SIZE
+        } else {
+            MEMORY_SIZE
+        };
+
+        let len = reader.len(STACK_SIZE, cell)?;
+        let mut stack: Vec<C> = Vec::with_capacity(len);
+        for _ in 0..len {
+            stack.push(reader.cell()?);
+        }
+
+        // メモリの大きさはヘッダで決まる
+        let len = reader.len(memory_size, 1 + cell)?;
+        if len != memory_size {
+            return Err(SnapshotError::InvalidLength(len));
+        }
+        let mut memory: Vec<Option<C>> = Vec::with_capacity(len);
+        for _ in 0..len {
+            let tag = reader.u8()?;
+            let val = reader.cell()?;
+            match tag {
+                0 => memory.push(None),
+                1 => memory.push(Some(val)),
+                _ => return Err(SnapshotError::InvalidMemoryTag(tag)),
+            }
+        }
+
+        let len = reader.len(CALL_SIZE, 16)?;
+        let mut call: Vec<Frame> = Vec::with_capacity(len);
+        for _ in 0..len {
+            let ret = reader.u32()?;
+            let fp = reader.u32()?;
+            let site = reader.u32()?;
+            let entry = reader.u32()?;
+            call.push(Frame {
+                ret,
+                fp,
+                site,
+                entry,
+            });
+        }
+
+        let len = reader.u32()?;
+        let program = reader.take(len)?;
+
+        if reader.pos != bytes.len() {
+            return Err(SnapshotError::TrailingData(bytes.len() - reader.pos));
+        }
+
+        let mut tokens = header.to_bytes();
+        tokens.extend_from_slice(program);
+
+        let config = VmConfig::default();
+        let mut vm = VM::create(tokens, in_port, out_port, debug_port, config);
+        vm.pc = pc;
+        vm.last_pc = pc;
+        vm.fp = fp;
+        vm.stack = stack;
+        vm.memory = memory;
+        vm.call = call;
+        vm.halted = halted;
+        vm.exit_code = exit_code;
+        vm.rng = Rng::new(rng);
+        vm.flags = Flags::from_u8(flags);
+
+        Ok(vm)
+    }
+}
+impl<R: Read, W: Write> VM<R, W> {
+    pub fn from_snapshot(bytes: &[u8], in_port: R, out_port: W) -> Result<Self, SnapshotError> {
+        Self::from_snapshot_with_debug_port(bytes, in_port, out_port, std::io::stderr())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::OpCode;
+
+    #[test]
+    fn test_resume_from_snapshot() {
+        // PUSH 7, STOREI 0x10, CALL 0x07, FIN, (0x07) LOADI 0x10, ADDI 1, RET
+        let code = vec![
+            0x00,
+            b'T',
+            b'W',
+            b'N',
+            OpCode::Push as u8,
+            7,
+            OpCode::StoreI as u8,
+            0x10,
+            OpCode::Call as u8,
+            0x07,
+            OpCode::Fin as u8,
+            OpCode::LoadI as u8,
+            0x10,
+            OpCode::AddI as u8,
+            1,
+            OpCode::Ret as u8,
+        ];
+        let mut vm = VM::new(code, std::io::empty(), std::io::sink());
+        vm.run_for(4).unwrap();
+        assert_eq!(
+            vm.call,
+            vec![Frame {
+                ret: 0x05,
+                fp: 0,
+                site: 0x04,
+                entry: 0x07
+            }]
+        );
+
+        let snapshot = vm.snapshot();
+        let mut resumed = VM::from_snapshot(&snapshot, std::io::empty(), std::io::sink()).unwrap();
+        assert_eq!(resumed.memory, vm.memory);
+        assert_eq!(resumed.snapshot(), snapshot);
+
+        resumed.run().unwrap();
+        assert!(resumed.halted);
+        assert_eq!(resumed.stack, vec![8]);
+    }
+
+    #[test]
+    fn test_invalid_snapshot() {
+        let vm = VM::new(
+            vec![0x00, b'T', b'W', b'N'],
+            std::io::empty(),
+            std::io::sink(),
+        );
+        let mut snapshot = vm.snapshot();
+
+        snapshot[4] = SNAPSHOT_VERSION + 1;
+        match VM::from_snapshot(&snapshot, std::io::empty(), std::io::sink()) {
+            Err(SnapshotError::UnsupportedVersion(_)) => (),
+            _ => panic!("Expected UnsupportedVersion error"),
+        }
+
+        snapshot[4] = SNAPSHOT_VERSION;
+        snapshot.truncate(10);
+        match VM::from_snapshot(&snapshot, std::io::empty(), std::io::sink()) {
+            Err(SnapshotError::UnexpectedEof) => (),
+            _ => panic!("Expected UnexpectedEof error"),
+        }
+
+        // 壊れた長さ (メモリの長さの位置) は確保する前にエラーにする
+        let mut snapshot = vm.snapshot();
+        assert_eq!(snapshot[29..33], 256u32.to_le_bytes());
+        snapshot[29..33].copy_from_slice(&u32::MAX.to_le_bytes());
+        match VM::from_snapshot(&snapshot, std::io::empty(), std::io::sink()) {
+            Err(SnapshotError::InvalidLength(_)) => (),
+            _ => panic!("Expected InvalidLength error"),
+        }
+
+        // VM::create では作れない大きさのメモリも受け付けない
+        let mut snapshot = vm.snapshot();
+        snapshot[29..33].copy_from_slice(&17u32.to_le_bytes());
+        match VM::from_snapshot(&snapshot, std::io::empty(), std::io::sink()) {
+            Err(SnapshotError::InvalidLength(17)) => (),
+            _ => panic!("Expected InvalidLength error"),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::io::{Read, Stderr, Write};
+use std::sync::Arc;
+
+use crate::cell::Cell;
+use crate::vm::{VM, VmErrorKind};
+
+// 組み込みシステムコールの番号 (VM::new で登録される)
+pub const SYS_READ: u8 = 0;
+pub const SYS_PRINT: u8 = 1;
+pub const SYS_DUMP: u8 = 2;
+pub const SYS_EXIT: u8 = 3;
+pub const SYS_PRINT_DEC: u8 = 4;
+pub const SYS_PRINT_HEX: u8 = 5;
+pub const SYS_PRINT_BIN: u8 = 6;
+pub const SYS_READ_DEC: u8 = 7;
+pub const SYS_PRINT_STR: u8 = 8;
+pub const SYS_PRINT_CSTR: u8 = 9;
+pub const SYS_RANDOM: u8 = 10;
+pub const SYS_TIME: u8 = 11;
+pub const SYS_SLEEP: u8 = 12;
+pub const SYS_ARGC: u8 = 13;
+pub const SYS_ARGV: u8 = 14;
+pub const SYS_GETENV: u8 = 15;
+pub const SYS_OPEN: u8 = 16;
+pub const SYS_FREAD: u8 = 17;
+pub const SYS_FWRITE: u8 = 18;
+pub const SYS_CLOSE: u8 = 19;
+
+// SYSCALL 命令から呼ばれる処理
+// 引数と戻り値は VM のスタックとメモリを通してやりとりする
+//
+// Fn(&mut VM<R, W, D, C>) -> Result<(), VmErrorKind> を満たすクロージャや関数はそのまま登録できる
+pub trait SyscallHandler<R: Read, W: Write, D: Write = Stderr, C: Cell = u8> {
+    fn call(&self, vm: &mut VM<R, W, D, C>) -> Result<(), VmErrorKind>;
+}
+impl<R: Read, W: Write, D: Write, C: Cell, F> SyscallHandler<R, W, D, C> for F
+where
+    F: Fn(&mut VM<R, W, D, C>) -> Result<(), VmErrorKind>,
+{
+    fn call(&self, vm: &mut VM<R, W, D, C>) -> Result<(), VmErrorKind> {
+        self(vm)
+    }
+}
+
+// 組み込みシステムコール (VM のメソッド)
+pub type BuiltinSyscall<R, W, D, C> = fn(&mut VM<R, W, D, C>) -> Result<(), VmErrorKind>;
+
+// VM.syscalls に登録されたシステムコール
+// 組み込みは関数ポインタで持つので、入出力の型に 'static を求めない
+pub enum Syscall<R: Read, W: Write, D: Write = Stderr, C: Cell = u8> {
+    Builtin(BuiltinSyscall<R, W, D, C>),
+    Custom(Arc<dyn SyscallHandler<R, W, D, C> + Send + Sync>),
+}
+impl<R: Read, W: Write, D: Write, C: Cell> Syscall<R, W, D, C> {
+    pub fn call(&self, vm: &mut VM<R, W, D, C>) -> Result<(), VmErrorKind> {
+        match self {
+            Self::Builtin(handler) => handler(vm),
+            Self::Custom(handler) => handler.call(vm),
+        }
+    }
+}
+impl<R: Read, W: Write, D: Write, C: Cell> Clone for Syscall<R, W, D, C> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Builtin(handler) => Self::Builtin(*handler),
+            Self::Custom(handler) => Self::Custom(Arc::clone(handler)),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::header::Header;
+use crate::opcode::OpCode;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    InvalidOpcode(usize, u8),        // 知らない命令 (アドレス, 値)
+    TruncatedOperand(usize),         // 即値の途中でプログラムが終わった
+    InvalidJumpTarget(usize, usize), // 命令の先頭以外への即値ジャンプ (アドレス, 飛び先)
+}
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidOpcode(addr, opcode) => {
+                write!(f, "Invalid opcode {:02X} at 0x{:04X}", opcode, addr)
+            }
+            Self::TruncatedOperand(addr) => write!(f, "Truncated operand at 0x{:04X}", addr),
+            Self::InvalidJumpTarget(addr, dst) => write!(
+                f,
+                "Jump at 0x{:04X} targets 0x{:04X}, which is not an instruction",
+                addr, dst
+            ),
+        }
+    }
+}
+
+// 命令から次に実行されうる場所
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Static(usize), // 即値や次の命令で決まる飛び先
+    Dynamic,       // 実行時にスタックの値で決まる飛び先 (JMPS / CALLS / RET)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub addr: usize,
+    pub opcode: OpCode,
+    pub operand: Option<usize>,
+    pub len: usize,
+}
+impl Instruction {
+    pub fn next(&self) -> usize {
+        self.addr + self.len
+    }
+
+    pub fn edges(&self) -> Vec<Edge> {
+        let next = Edge::Static(self.next());
+        let target = self.operand.map(Edge::Static);
+
+        match self.opcode {
+            OpCode::Fin => vec![],
+            OpCode::Jmz => target.into_iter().collect(),
+            OpCode::JmpS | OpCode::Ret => vec![Edge::Dynamic],
+            OpCode::CallS => vec![Edge::Dynamic, next],
+            opcode if opcode.is_jump() => target.into_iter().chain([next]).collect(),
+            _ => vec![next],
+        }
+    }
+}
+
+// addr にある命令を 1 つ読む
+fn decode_at(program: &[u8], header: Header, addr: usize) -> Result<Instruction, VerifyError> {
+    let opcode =
+        OpCode::from_u8(program[addr]).ok_or(VerifyError::InvalidOpcode(addr, program[addr]))?;
+    let operand_len = opcode.operand_len(header);
+    let operand = match operand_len {
+        0 => None,
+        _ => {
+            let bytes = program
+                .get(addr + 1..addr + 1 + operand_len)
+                .ok_or(VerifyError::TruncatedOperand(addr))?;
+            // 即値はリトルエンディアン
+            Some(
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |acc, byte| acc << 8 | *byte as usize),
+            )
+        }
+    };
+
+    Ok(Instruction {
+        addr,
+        opcode,
+        operand,
+        len: 1 + operand_len,
+    })
+}
+
+// 実行前の検査
+// プログラム (マジックナンバーを除いたもの) のうち、0 番地から静的な辺で辿れる命令だけを読む
+// 辿れない場所はデータかもしれないので読まない (JMPS / CALLS / RET の飛び先は実行時まで分からない)
+// 即値で決まる飛び先は命令の先頭 (またはプログラムの末尾) でなければならない
+pub fn verify(program: &[u8], header: Header) -> Result<Vec<Instruction>, VerifyError> {
+    let mut instructions: BTreeMap<usize, Instruction> = BTreeMap::new();
+    // 飛び先 -> そこへ最初に飛んだ命令のアドレス
+    let mut sources: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut pending = BTreeSet::from([0]);
+
+    while let Some(addr) = pending.pop_first() {
+        if addr >= program.len() || instructions.contains_key(&addr) {
+            continue;
+        }
+        // 既に読んだ命令の途中に飛び込んでいないか
+        if let Some((_, previous)) = instructions.range(..addr).next_back()
+            && previous.next() > addr
+        {
+            return Err(VerifyError::InvalidJumpTarget(sources[&addr], addr));
+        }
+
+        let instruction = decode_at(program, header, addr)?;
+        // この命令のオペランドの途中が既に命令の先頭として読まれていないか
+        if let Some((&start, _)) = instructions.range(addr + 1..instruction.next()).next() {
+            return Err(VerifyError::InvalidJumpTarget(sources[&start], start));
+        }
+
+        for edge in instruction.edges() {
+            if let Edge::Static(dst) = edge {
+                // 飛び先はプログラムの中か末尾でなければならない
+                if dst > program.len() {
+                    return Err(VerifyError::InvalidJumpTarget(addr, dst));
+                }
+                sources.entry(dst).or_insert(addr);
+                pending.insert(dst);
+            }
+        }
+        instructions.insert(addr, instruction);
+    }
+
+    Ok(instructions.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        // (0x00) PUSH 0x04, JMPS, (0x03) FIN, (0x04) PUSH 0x03, CALLS, JMZ 0x03
+        let program = vec![
+            OpCode::Push as u8,
+            0x04,
+            OpCode::JmpS as u8,
+            OpCode::Fin as u8,
+            OpCode::Push as u8,
+            0x03,
+            OpCode::CallS as u8,
+            OpCode::Jmz as u8,
+            0x03,
+        ];
+        // JMPS の先は静的には辿れないので読まない
+        let instructions = verify(&program, Header::default()).unwrap();
+        assert_eq!(instructions.len(), 2);
+        assert_eq!(instructions[1].edges(), vec![Edge::Dynamic]);
+
+        // (0x00) PUSH 0x04, CALLS, FIN, (0x04) RET
+        let program = vec![
+            OpCode::Push as u8,
+            0x04,
+            OpCode::CallS as u8,
+            OpCode::Fin as u8,
+            OpCode::Ret as u8,
+        ];
+        let instructions = verify(&program, Header::default()).unwrap();
+        assert_eq!(instructions.len(), 3);
+        assert_eq!(
+            instructions[1].edges(),
+            vec![Edge::Dynamic, Edge::Static(0x03)]
+        );
+
+        // オペランドの途中へのジャンプ
+        let program = vec![OpCode::Jz as u8, 0x03, OpCode::Push as u8, 0x01];
+        assert_eq!(
+            verify(&program, Header::default()),
+            Err(VerifyError::InvalidJumpTarget(0x00, 0x03))
+        );
+
+        assert_eq!(
+            verify(&[OpCode::Push as u8], Header::default()),
+            Err(VerifyError::TruncatedOperand(0x00))
+        );
+        assert_eq!(
+            verify(&[0xEE], Header::default()),
+            Err(VerifyError::InvalidOpcode(0x00, 0xEE))
+        );
+
+        // ワイドモードではジャンプ先が 16bit
+        let program = vec![OpCode::Jmz as u8, 0x00, 0x01];
+        assert_eq!(
+            verify(
+                &program,
+                Header {
+                    wide: true,
+                    ..Default::default()
+                }
+            ),
+            Err(VerifyError::InvalidJumpTarget(0x00, 0x0100))
+        );
+    }
+
+    #[test]
+    fn test_verify_trailing_data() {
+        // PUSH 0x41, PUSH 1, SYSCALL, FIN のあとに命令ではないデータが続く
+        let program = vec![
+            OpCode::Push as u8,
+            0x41,
+            OpCode::Push as u8,
+            0x01,
+            OpCode::SysCall as u8,
+            OpCode::Fin as u8,
+            0xEE,
+            OpCode::Push as u8,
+        ];
+        let instructions = verify(&program, Header::default()).unwrap();
+        assert_eq!(instructions.len(), 4);
+
+        let mut tokens = vec![0x00, b'T', b'W', b'N'];
+        tokens.extend(&program);
+        let mut vm = crate::vm::VM::new(tokens, std::io::empty(), Vec::new());
+        vm.run().unwrap();
+        assert_eq!(vm.out_port, b"A");
+    }
+}
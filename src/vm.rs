@@ -1,40 +1,83 @@
-use std::io::{Read, Write};
-
+use std::collections::HashMap;
+use std::io::{Read, Stderr, Write};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cell::Cell;
+use crate::clock::{Clock, RealClock};
+use crate::coverage::Coverage;
+use crate::debug::DebugInfo;
+use crate::dump::DumpFormat;
+use crate::flags::Flags;
+use crate::fs::{FileSystem, OpenFile, OpenMode};
+use crate::header::Header;
+use crate::history::{Change, History};
 use crate::opcode::OpCode;
-
-const MEMORY_SIZE: usize = 256;
-const STACK_SIZE: usize = 256;
-const CALL_SIZE: usize = 256;
+use crate::profile::Profile;
+use crate::rng::Rng;
+use crate::syscall::{self, Syscall, SyscallHandler};
+
+pub const MEMORY_SIZE: usize = 256;
+pub const WIDE_MEMORY_SIZE: usize = 65536;
+pub const STACK_SIZE: usize = 256;
+pub const CALL_SIZE: usize = 256;
+const FILES_SIZE: usize = 16;
 const BYTE_SIZE: u8 = 1;
 
 #[derive(Debug)]
 pub enum SysError {
     InvalidCharacter,
+    InvalidNumber(String),    // 数値として読めない入力
+    ValueTooLarge(usize),     // 1セルに収まらない値 (引数の個数や文字列の長さ)
+    NoSuchArgument(u64),      // 存在しない番号のプログラム引数
+    InvalidPath(String),      // ルートの外を指すパス
+    FileNotFound(String),     // ファイルが存在しない
+    PermissionDenied(String), // 許可されていない書き込み
+    InvalidOpenMode(u64),     // 知らないオープンモード
+    InvalidHandle(u64),       // 開いていないファイルハンドル
+    TooManyFiles,             // 同時に開けるファイル数を超えた
+    Io(String),               // その他の入出力エラー
 }
 impl std::fmt::Display for SysError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::InvalidCharacter => write!(f, "Invalid character"),
+            Self::InvalidNumber(input) => write!(f, "Invalid number: {:?}", input),
+            Self::ValueTooLarge(n) => write!(f, "Value too large for a cell: {}", n),
+            Self::NoSuchArgument(n) => write!(f, "No such argument: {}", n),
+            Self::InvalidPath(path) => write!(f, "Invalid path: {:?}", path),
+            Self::FileNotFound(path) => write!(f, "File not found: {:?}", path),
+            Self::PermissionDenied(path) => write!(f, "Permission denied: {:?}", path),
+            Self::InvalidOpenMode(mode) => write!(f, "Invalid open mode: {:02X}", mode),
+            Self::InvalidHandle(handle) => write!(f, "Invalid file handle: {:02X}", handle),
+            Self::TooManyFiles => write!(f, "Too many open files"),
+            Self::Io(e) => write!(f, "I/O error: {}", e),
         }
     }
 }
 
+impl std::error::Error for SysError {}
+
+// 何が起きたか (命令やシステムコールの処理はこれを返し、step が位置を付けて VmError にする)
 #[derive(Debug)]
-pub enum VmError {
+pub enum VmErrorKind {
     StackUnderflow,             // POPしようとしたがスタックが空
     StackOverflow,              // スタックが上限を超えた
     CallUnderflow,              // POPしようとしたがコールスタックが空
     CallOverflow,               // コールスタックが上限を超えた
     DivisionByZero,             // 0で割ろうとした
+    ArithmeticOverflow,         // 演算結果が範囲を超えた (ArithMode::Trap の場合)
     InvalidOpcode(u8),          // 知らない命令が来た
     InvalidMemoryAccess(usize), // メモリ範囲外にアクセスした
+    InvalidLocal(i8),           // LOADL / STOREL がスタックの外を指した
     UninitializedMemory(usize), // まだ値の入っていないメモリにアクセスした
-    UnexpectedSysCall(u8),      // 知らないシステムコールが来た
+    UnexpectedSysCall(u64),     // 知らないシステムコールが来た
     UnexpectedEof,              // 命令の途中でファイルが終わった
 
     SysError(SysError), // SysCallでエラーが発生した
 }
-impl std::fmt::Display for VmError {
+impl std::fmt::Display for VmErrorKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::StackUnderflow => write!(f, "Stack underflow"),
@@ -42,8 +85,10 @@ impl std::fmt::Display for VmError {
             Self::CallUnderflow => write!(f, "Call underflow"),
             Self::CallOverflow => write!(f, "Call Overflow"),
             Self::DivisionByZero => write!(f, "Division by zero"),
+            Self::ArithmeticOverflow => write!(f, "Arithmetic overflow"),
             Self::InvalidOpcode(opcode) => write!(f, "Invalid Opcode: {:02X}", opcode),
             Self::InvalidMemoryAccess(dst) => write!(f, "Invalid memory access: {:02X}", dst),
+            Self::InvalidLocal(k) => write!(f, "Invalid local variable: {}", k),
             Self::UninitializedMemory(dst) => write!(f, "Not exist designated memory: {:02X}", dst),
             Self::UnexpectedSysCall(n) => write!(f, "Unexpected SysCall: {:02X}", n),
             Self::UnexpectedEof => write!(f, "Unexpected EOF"),
@@ -52,431 +97,2625 @@ impl std::fmt::Display for VmError {
         }
     }
 }
+impl VmErrorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::StackUnderflow => "StackUnderflow",
+            Self::StackOverflow => "StackOverflow",
+            Self::CallUnderflow => "CallUnderflow",
+            Self::CallOverflow => "CallOverflow",
+            Self::DivisionByZero => "DivisionByZero",
+            Self::ArithmeticOverflow => "ArithmeticOverflow",
+            Self::InvalidOpcode(_) => "InvalidOpcode",
+            Self::InvalidMemoryAccess(_) => "InvalidMemoryAccess",
+            Self::InvalidLocal(_) => "InvalidLocal",
+            Self::UninitializedMemory(_) => "UninitializedMemory",
+            Self::UnexpectedSysCall(_) => "UnexpectedSysCall",
+            Self::UnexpectedEof => "UnexpectedEof",
+            Self::SysError(_) => "SysError",
+        }
+    }
+
+    // twnvm の終了コード (プログラムが EXIT で使う 0-199 と重ならないように 210 から割り当てる)
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::StackUnderflow => 210,
+            Self::StackOverflow => 211,
+            Self::CallUnderflow => 212,
+            Self::CallOverflow => 213,
+            Self::DivisionByZero => 214,
+            Self::ArithmeticOverflow => 215,
+            Self::InvalidOpcode(_) => 216,
+            Self::InvalidMemoryAccess(_) => 217,
+            Self::InvalidLocal(_) => 218,
+            Self::UninitializedMemory(_) => 219,
+            Self::UnexpectedSysCall(_) => 220,
+            Self::UnexpectedEof => 221,
+            Self::SysError(_) => 222,
+        }
+    }
+}
+impl std::error::Error for VmErrorKind {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::SysError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// 実行時エラー (失敗した命令の位置と、その命令を実行する直前の状態を持つ)
+// 命令がオペランドを取り出してから失敗しても、原因になった値が残るように実行前の状態を記録する
+#[derive(Debug)]
+pub struct VmError {
+    pub kind: VmErrorKind,
+    pub pc: usize,               // 失敗した命令のアドレス
+    pub opcode: Option<OpCode>,  // 失敗した命令 (知らない命令やプログラムの外なら None)
+    pub depth: usize,            // スタックの深さ
+    pub stack_top: Option<i128>, // スタックトップの値 (空なら None)
+    pub call_depth: usize,       // コールスタックの深さ
+}
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at address 0x{:02X}", self.kind, self.pc)?;
+        if let Some(opcode) = self.opcode {
+            write!(f, ", {}", opcode.name())?;
+        }
+        write!(f, ")")
+    }
+}
+impl std::error::Error for VmError {
+    // メッセージは kind のものを含むので、その原因 (SysError) を返す
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.kind.source()
+    }
+}
+
+// 算術演算の結果がセルの範囲を超えたときの扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArithMode {
+    #[default]
+    Saturate, // 範囲の端に丸める
+    Wrap, // 桁あふれした分を捨てる (2の補数)
+    Trap, // VmErrorKind::ArithmeticOverflow で停止する
+}
+impl ArithMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "saturate" => Some(Self::Saturate),
+            "wrap" => Some(Self::Wrap),
+            "trap" => Some(Self::Trap),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VmConfig {
+    pub seed: Option<u64>, // RANDOM のシード (None なら OS のエントロピーから)
+    pub args: Vec<String>, // ARGC / ARGV で読めるプログラム引数
+    pub env: HashMap<String, String>, // GETENV で読める環境変数 (許可したものだけを入れる)
+    pub dump_format: DumpFormat, // DUMP の出力形式
+    pub arith_mode: ArithMode, // 算術演算のオーバーフローの扱い
+}
+
+// コールスタックの1段
+// CALL で戻りアドレスと呼び出し元のフレームポインタを積み、RET で両方を戻す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub ret: usize,
+    pub fp: usize,
+    pub site: usize, // 呼び出した命令 (CALL / CALLS) のアドレス (バックトレースに使う)
+    pub entry: usize, // 呼び出し先のアドレス (プロファイルのフレーム名に使う)
+}
 
-pub struct VM<R: Read, W: Write> {
+// D は DUMP の出力先 (デバッグポート)、C はスタックとメモリのセルの型
+pub struct VM<R: Read, W: Write, D: Write = Stderr, C: Cell = u8> {
     pub pc: usize,
-    pub stack: Vec<u8>,
-    pub memory: Vec<Option<u8>>,
-    pub call: Vec<usize>,
+    pub last_pc: usize, // 最後に実行を始めた命令のアドレス (エラーの位置の表示に使う)
+    pub fp: usize,      // フレームポインタ (ENTER したときのスタックの深さ)
+    pub stack: Vec<C>,
+    pub memory: Vec<Option<C>>,
+    pub call: Vec<Frame>,
+    pub flags: Flags,
+    pub header: Header,
     pub tokens: Vec<u8>,
     pub halted: bool,
     pub exit_code: u8,
+    pub profile: Option<Profile>,
+    pub coverage: Option<Coverage>,
+    pub history: Option<History<C>>,
+    pub syscalls: HashMap<u8, Syscall<R, W, D, C>>,
+    pub rng: Rng,
+    pub clock: Box<dyn Clock>,
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub fs: Option<Box<dyn FileSystem>>,
+    pub files: Vec<Option<OpenFile>>,
+    pub dump_format: DumpFormat,
+    pub arith_mode: ArithMode,
+    pub debug_info: Option<DebugInfo>, // DUMP でアドレスにラベルを付けるのに使う
 
     pub in_port: R,
     pub out_port: W,
+    pub debug_port: D,
 }
 impl<R: Read, W: Write> VM<R, W> {
-    pub fn new(mut tokens: Vec<u8>, in_port: R, out_port: W) -> Self {
-        if tokens.len() >= "\0TWN".len() && &tokens[0..4] == [0x00, b'T', b'W', b'N'] {
-            tokens.drain(0..4);
-        } else {
-            panic!("Invalid file format: Magic number not found");
+    pub fn new(tokens: Vec<u8>, in_port: R, out_port: W) -> Self {
+        Self::with_config(tokens, in_port, out_port, VmConfig::default())
+    }
+
+    pub fn with_config(tokens: Vec<u8>, in_port: R, out_port: W, config: VmConfig) -> Self {
+        Self::with_debug_port(tokens, in_port, out_port, std::io::stderr(), config)
+    }
+}
+impl<R: Read, W: Write, D: Write> VM<R, W, D> {
+    pub fn with_debug_port(
+        tokens: Vec<u8>,
+        in_port: R,
+        out_port: W,
+        debug_port: D,
+        config: VmConfig,
+    ) -> Self {
+        Self::create(tokens, in_port, out_port, debug_port, config)
+    }
+}
+impl<R: Read, W: Write, D: Write, C: Cell> VM<R, W, D, C> {
+    // セルの型はヘッダに書かれたものと一致していなければならない
+    pub fn create(
+        mut tokens: Vec<u8>,
+        in_port: R,
+        out_port: W,
+        debug_port: D,
+        config: VmConfig,
+    ) -> Self {
+        let header = match Header::parse(&tokens) {
+            Some((header, len)) => {
+                tokens.drain(0..len);
+                header
+            }
+            None => panic!("Invalid file format: Magic number not found"),
+        };
+        if header.cell != C::WIDTH {
+            panic!(
+                "Invalid file format: The program uses {} cells, but the VM uses {}",
+                header.cell,
+                C::WIDTH
+            );
         }
+        let memory_size = if header.wide {
+            WIDE_MEMORY_SIZE
+        } else {
+            MEMORY_SIZE
+        };
 
-        Self {
+        let mut vm = Self {
             pc: 0,
+            last_pc: 0,
+            fp: 0,
             stack: Vec::new(),
-            memory: vec![None; MEMORY_SIZE],
+            memory: vec![None; memory_size],
             call: Vec::new(),
+            flags: Flags::default(),
+            header,
             tokens,
             halted: false,
             exit_code: 0u8,
+            profile: None,
+            coverage: None,
+            history: None,
+            syscalls: HashMap::new(),
+            rng: match config.seed {
+                Some(seed) => Rng::new(seed),
+                None => Rng::from_entropy(),
+            },
+            clock: Box::new(RealClock::new()),
+            args: config.args,
+            env: config.env,
+            fs: None,
+            files: Vec::new(),
+            dump_format: config.dump_format,
+            arith_mode: config.arith_mode,
+            debug_info: None,
 
             in_port,
             out_port,
-        }
+            debug_port,
+        };
+
+        vm.syscalls
+            .insert(syscall::SYS_READ, Syscall::Builtin(Self::sys_read));
+        vm.syscalls
+            .insert(syscall::SYS_PRINT, Syscall::Builtin(Self::sys_print));
+        vm.syscalls
+            .insert(syscall::SYS_DUMP, Syscall::Builtin(Self::sys_dump));
+        vm.syscalls
+            .insert(syscall::SYS_EXIT, Syscall::Builtin(Self::sys_exit));
+        vm.syscalls.insert(
+            syscall::SYS_PRINT_DEC,
+            Syscall::Builtin(Self::sys_print_dec),
+        );
+        vm.syscalls.insert(
+            syscall::SYS_PRINT_HEX,
+            Syscall::Builtin(Self::sys_print_hex),
+        );
+        vm.syscalls.insert(
+            syscall::SYS_PRINT_BIN,
+            Syscall::Builtin(Self::sys_print_bin),
+        );
+        vm.syscalls
+            .insert(syscall::SYS_READ_DEC, Syscall::Builtin(Self::sys_read_dec));
+        vm.syscalls.insert(
+            syscall::SYS_PRINT_STR,
+            Syscall::Builtin(Self::sys_print_str),
+        );
+        vm.syscalls.insert(
+            syscall::SYS_PRINT_CSTR,
+            Syscall::Builtin(Self::sys_print_cstr),
+        );
+        vm.syscalls
+            .insert(syscall::SYS_RANDOM, Syscall::Builtin(Self::sys_random));
+        vm.syscalls
+            .insert(syscall::SYS_TIME, Syscall::Builtin(Self::sys_time));
+        vm.syscalls
+            .insert(syscall::SYS_SLEEP, Syscall::Builtin(Self::sys_sleep));
+        vm.syscalls
+            .insert(syscall::SYS_ARGC, Syscall::Builtin(Self::sys_argc));
+        vm.syscalls
+            .insert(syscall::SYS_ARGV, Syscall::Builtin(Self::sys_argv));
+        vm.syscalls
+            .insert(syscall::SYS_GETENV, Syscall::Builtin(Self::sys_getenv));
+        vm.syscalls
+            .insert(syscall::SYS_OPEN, Syscall::Builtin(Self::sys_open));
+        vm.syscalls
+            .insert(syscall::SYS_FREAD, Syscall::Builtin(Self::sys_fread));
+        vm.syscalls
+            .insert(syscall::SYS_FWRITE, Syscall::Builtin(Self::sys_fwrite));
+        vm.syscalls
+            .insert(syscall::SYS_CLOSE, Syscall::Builtin(Self::sys_close));
+
+        vm
+    }
+
+    // n 番のシステムコールを登録する (既に登録されていれば置き換える)
+    pub fn register_syscall<H>(&mut self, n: u8, handler: H)
+    where
+        H: SyscallHandler<R, W, D, C> + Send + Sync + 'static,
+    {
+        self.syscalls.insert(n, Syscall::Custom(Arc::new(handler)));
+    }
+
+    pub fn unregister_syscall(&mut self, n: u8) -> Option<Syscall<R, W, D, C>> {
+        self.syscalls.remove(&n)
     }
 
-    pub fn next_byte(&mut self) -> Result<u8, VmError> {
+    pub fn next_byte(&mut self) -> Result<u8, VmErrorKind> {
         if self.tokens.len() - 1 <= self.pc {
-            return Err(VmError::UnexpectedEof);
+            return Err(VmErrorKind::UnexpectedEof);
         }
 
-        self.pc += 1 * (BYTE_SIZE as usize);
+        self.pc += BYTE_SIZE as usize;
 
         Ok(self.tokens[self.pc])
     }
 
-    pub fn push_stack(&mut self, content: u8) -> Result<(), VmError> {
+    // 即値のアドレス (ワイドモードでは 16bit リトルエンディアン)
+    pub fn next_addr(&mut self) -> Result<usize, VmErrorKind> {
+        let lo = self.next_byte()? as usize;
+        if !self.header.wide {
+            return Ok(lo);
+        }
+
+        let hi = self.next_byte()? as usize;
+        Ok(hi << 8 | lo)
+    }
+
+    // 即値の値 (セルの幅のリトルエンディアン)
+    pub fn next_cell(&mut self) -> Result<C, VmErrorKind> {
+        let mut bits: u64 = 0;
+        for i in 0..C::WIDTH.bytes() {
+            bits |= (self.next_byte()? as u64) << (i * 8);
+        }
+
+        Ok(C::from_bits(bits))
+    }
+
+    // スタック上のアドレス
+    // 8bit セルのワイドモードでは下位バイトがスタックトップ、その下が上位バイト
+    pub fn pop_addr(&mut self) -> Result<usize, VmErrorKind> {
+        let lo = self.pop_stack()?.to_bits() as usize;
+        if !self.header.wide || C::WIDTH.bytes() > 1 {
+            return Ok(lo);
+        }
+
+        let hi = self.pop_stack()?.to_bits() as usize;
+        Ok(hi << 8 | lo)
+    }
+
+    pub fn push_stack(&mut self, content: C) -> Result<(), VmErrorKind> {
         if STACK_SIZE <= self.stack.len() {
-            return Err(VmError::StackOverflow);
+            return Err(VmErrorKind::StackOverflow);
         }
 
         self.stack.push(content);
+        self.record(Change::Push);
 
         Ok(())
     }
 
-    pub fn pop_stack(&mut self) -> Result<u8, VmError> {
+    pub fn pop_stack(&mut self) -> Result<C, VmErrorKind> {
         if self.stack.is_empty() {
-            return Err(VmError::StackUnderflow);
+            return Err(VmErrorKind::StackUnderflow);
+        }
+
+        let content = self.stack.pop().unwrap();
+        self.record(Change::Pop(content));
+
+        Ok(content)
+    }
+
+    // 途中まで取り出してから失敗しないように、先に深さを確かめる
+    fn check_depth(&self, n: usize) -> Result<(), VmErrorKind> {
+        if self.stack.len() < n {
+            return Err(VmErrorKind::StackUnderflow);
+        }
+
+        Ok(())
+    }
+
+    // 比較の結果 (成り立てば 0) をプッシュする
+    fn push_cond(&mut self, holds: bool) -> Result<(), VmErrorKind> {
+        self.push_stack(C::from_u8(if holds { 0 } else { 1 }))
+    }
+
+    // 本来の結果 n が range に収まらなければ arith_mode に従う
+    fn overflow(&self, n: i128, range: RangeInclusive<i128>) -> Result<C, VmErrorKind> {
+        if range.contains(&n) {
+            return Ok(C::wrap(n));
+        }
+
+        match self.arith_mode {
+            ArithMode::Saturate => Ok(C::wrap(n.clamp(*range.start(), *range.end()))),
+            ArithMode::Wrap => Ok(C::wrap(n)),
+            ArithMode::Trap => Err(VmErrorKind::ArithmeticOverflow),
         }
+    }
 
-        Ok(self.stack.pop().unwrap())
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.record(Change::Flags(self.flags));
+        self.flags = flags;
     }
 
-    pub fn store_memory(&mut self, content: u8, dst: usize) -> Result<(), VmError> {
-        if MEMORY_SIZE <= dst {
-            return Err(VmError::InvalidMemoryAccess(dst));
+    pub fn store_memory(&mut self, content: C, dst: usize) -> Result<(), VmErrorKind> {
+        if self.memory.len() <= dst {
+            return Err(VmErrorKind::InvalidMemoryAccess(dst));
         }
 
+        self.record(Change::Store(dst, self.memory[dst]));
         self.memory[dst] = Some(content);
 
         Ok(())
     }
 
-    pub fn load_memory(&self, dst: usize) -> Result<Option<u8>, VmError> {
-        if MEMORY_SIZE <= dst {
-            return Err(VmError::InvalidMemoryAccess(dst));
+    pub fn load_memory(&self, dst: usize) -> Result<C, VmErrorKind> {
+        if self.memory.len() <= dst {
+            return Err(VmErrorKind::InvalidMemoryAccess(dst));
         }
 
         match self.memory[dst] {
-            Some(content) => return Ok(Some(content)),
-            None => return Err(VmError::UninitializedMemory(dst)),
+            Some(content) => Ok(content),
+            None => Err(VmErrorKind::UninitializedMemory(dst)),
         }
     }
 
-    pub fn push_call(&mut self, content: usize) -> Result<(), VmError> {
+    // 戻りアドレスを現在のフレームポインタと、実行中の命令のアドレスと一緒に積む
+    pub fn push_call(&mut self, ret: usize, entry: usize) -> Result<(), VmErrorKind> {
         if CALL_SIZE <= self.call.len() {
-            return Err(VmError::CallOverflow);
+            return Err(VmErrorKind::CallOverflow);
         }
 
-        self.call.push(content);
+        self.call.push(Frame {
+            ret,
+            fp: self.fp,
+            site: self.last_pc,
+            entry,
+        });
+        self.record(Change::PushCall);
 
         Ok(())
     }
 
-    pub fn pop_call(&mut self) -> Result<usize, VmError> {
+    pub fn pop_call(&mut self) -> Result<Frame, VmErrorKind> {
         if self.call.is_empty() {
-            return Err(VmError::CallUnderflow);
+            return Err(VmErrorKind::CallUnderflow);
+        }
+
+        let content = self.call.pop().unwrap();
+        self.record(Change::PopCall(content));
+
+        Ok(content)
+    }
+
+    pub fn set_fp(&mut self, fp: usize) {
+        self.record(Change::Fp(self.fp));
+        self.fp = fp;
+    }
+
+    // フレームポインタから k 番目のスタックの位置 (負なら引数)
+    fn local(&self, k: i8) -> Result<usize, VmErrorKind> {
+        match self.fp.checked_add_signed(k as isize) {
+            Some(idx) if idx < self.stack.len() => Ok(idx),
+            _ => Err(VmErrorKind::InvalidLocal(k)),
+        }
+    }
+
+    fn record(&mut self, change: Change<C>) {
+        if let Some(history) = &mut self.history {
+            history.record(change);
+        }
+    }
+
+    // 直前に実行した1命令を取り消す (履歴が無ければ false)
+    pub fn step_back(&mut self) -> bool {
+        let Some(step) = self.history.as_mut().and_then(|h| h.steps.pop_back()) else {
+            return false;
+        };
+
+        for change in step.changes.into_iter().rev() {
+            match change {
+                Change::Push => {
+                    self.stack.pop();
+                }
+                Change::Pop(content) => self.stack.push(content),
+                Change::Store(dst, content) => self.memory[dst] = content,
+                Change::PushCall => {
+                    self.call.pop();
+                }
+                Change::PopCall(content) => self.call.push(content),
+                Change::Rng(state) => self.rng.state = state,
+                Change::Flags(flags) => self.flags = flags,
+                Change::Fp(fp) => self.fp = fp,
+                Change::Local(idx, content) => self.stack[idx] = content,
+            }
+        }
+
+        self.pc = step.pc;
+        self.halted = step.halted;
+        self.exit_code = step.exit_code;
+
+        true
+    }
+
+    // addr に最後に書き込んだ命令の実行直前まで巻き戻す
+    // 履歴の中に書き込みが無ければ何もせず false を返す
+    pub fn rewind_to_write(&mut self, addr: usize) -> bool {
+        let Some(n) = self.history.as_ref().and_then(|h| h.last_write(addr)) else {
+            return false;
+        };
+
+        for _ in 0..=n {
+            self.step_back();
         }
 
-        Ok(self.call.pop().unwrap())
+        true
     }
 
-    pub fn sys_read(&mut self) -> Result<(), VmError> {
+    pub fn sys_read(&mut self) -> Result<(), VmErrorKind> {
         let mut buffer = [0u8; 1];
 
         match self.in_port.read(&mut buffer) {
             Ok(0) => {
-                self.push_stack(0)?;
+                self.push_stack(C::from_u8(0))?;
             }
             Ok(_) => {
-                self.push_stack(buffer[0])?;
+                self.push_stack(C::from_u8(buffer[0]))?;
             }
             Err(_) => {
-                return Err(VmError::SysError(SysError::InvalidCharacter));
+                return Err(VmErrorKind::SysError(SysError::InvalidCharacter));
             }
         }
         Ok(())
     }
 
-    pub fn sys_print(&mut self) -> Result<(), VmError> {
-        let target = self.pop_stack()?;
+    pub fn sys_print(&mut self) -> Result<(), VmErrorKind> {
+        // セルの下位バイトを1文字として出力する
+        let target = self.pop_stack()?.to_bits() as u8;
 
         write!(self.out_port, "{}", target as char)
-            .map_err(|_| VmError::SysError(SysError::InvalidCharacter))?;
+            .map_err(|_| VmErrorKind::SysError(SysError::InvalidCharacter))?;
 
         self.out_port
             .flush()
-            .map_err(|_| VmError::SysError(SysError::InvalidCharacter))?;
+            .map_err(|_| VmErrorKind::SysError(SysError::InvalidCharacter))?;
 
         Ok(())
     }
 
-    pub fn sys_dump(&self) {
-        eprintln!("=== VM STATE ===");
-        eprintln!("STACK : {:?}", self.stack);
-        let memory = self
-            .memory
-            .chunk_by(|a, b| a == b)
-            .map(|chunk| (chunk[0], chunk.len()))
-            .collect::<Vec<(Option<u8>, usize)>>();
-        eprintln!("MEMORY: {:?}", memory);
-        eprintln!("==================");
-    }
+    fn write_out(&mut self, text: &str) -> Result<(), VmErrorKind> {
+        self.out_port
+            .write_all(text.as_bytes())
+            .map_err(|_| VmErrorKind::SysError(SysError::InvalidCharacter))?;
 
-    pub fn sys_exit(&mut self) -> Result<(), VmError> {
-        let code = self.pop_stack()?;
-        self.exit_code = code;
-        self.halted = true;
+        self.out_port
+            .flush()
+            .map_err(|_| VmErrorKind::SysError(SysError::InvalidCharacter))?;
 
         Ok(())
     }
 
-    pub fn run(&mut self) -> Result<(), VmError> {
-        while !self.halted && (self.pc < self.tokens.len()) {
-            let token = self.tokens[self.pc];
-
-            if let Some(opcode) = OpCode::from_u8(token) {
-                match opcode {
-                    OpCode::SysCall => {
-                        let n = self.pop_stack()?;
-                        match n {
-                            0 => self.sys_read()?,
-                            1 => self.sys_print()?,
-                            2 => self.sys_dump(),
-                            3 => self.sys_exit()?,
-                            _ => return Err(VmError::UnexpectedSysCall(n)),
-                        };
-                    }
-                    OpCode::Push => {
-                        let val = self.next_byte()?;
-                        self.push_stack(val)?;
-                    }
-                    OpCode::Pop => {
-                        self.pop_stack()?;
-                    }
-                    OpCode::Dup => {
-                        let a = self.pop_stack()?;
-                        self.push_stack(a)?;
-                        self.push_stack(a)?;
-                    }
-                    OpCode::Swap => {
-                        let a = self.pop_stack()?;
-                        let b = self.pop_stack()?;
-
-                        self.push_stack(a)?;
-                        self.push_stack(b)?;
-                    }
-                    OpCode::Add => {
-                        let b: u8 = self.pop_stack()?;
-                        let a: u8 = self.pop_stack()?;
+    pub fn sys_print_dec(&mut self) -> Result<(), VmErrorKind> {
+        let target = self.pop_stack()?;
 
-                        self.push_stack(a.saturating_add(b))?;
-                    }
-                    OpCode::Sub => {
-                        let b: u8 = self.pop_stack()?;
-                        let a: u8 = self.pop_stack()?;
+        self.write_out(&format!("{}", target.value()))
+    }
 
-                        self.push_stack(a.saturating_sub(b))?;
-                    }
-                    OpCode::Mul => {
-                        let b: u8 = self.pop_stack()?;
-                        let a: u8 = self.pop_stack()?;
+    pub fn sys_print_hex(&mut self) -> Result<(), VmErrorKind> {
+        let target = self.pop_stack()?;
 
-                        self.push_stack(a.saturating_mul(b))?;
-                    }
-                    OpCode::Div => {
-                        let b: u8 = self.pop_stack()?;
-                        let a: u8 = self.pop_stack()?;
+        self.write_out(&format!("{:X}", target.to_bits()))
+    }
 
-                        if b == 0 {
-                            return Err(VmError::DivisionByZero);
-                        }
+    pub fn sys_print_bin(&mut self) -> Result<(), VmErrorKind> {
+        let target = self.pop_stack()?;
 
-                        self.push_stack(a.saturating_div(b))?;
-                    }
-                    OpCode::Mod => {
-                        let b: u8 = self.pop_stack()?;
-                        let a: u8 = self.pop_stack()?;
+        self.write_out(&format!("{:b}", target.to_bits()))
+    }
 
-                        self.push_stack(a % b)?;
-                    }
-                    OpCode::AddI => {
-                        let b: u8 = self.next_byte()?;
-                        let a: u8 = self.pop_stack()?;
+    // 改行 (またはEOF) までを1行読み、10進数としてプッシュする
+    pub fn sys_read_dec(&mut self) -> Result<(), VmErrorKind> {
+        let mut line: Vec<u8> = Vec::new();
+        let mut buffer = [0u8; 1];
 
-                        self.push_stack(a.saturating_add(b))?;
-                    }
-                    OpCode::SubI => {
-                        let b: u8 = self.next_byte()?;
-                        let a: u8 = self.pop_stack()?;
+        loop {
+            match self.in_port.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(_) if buffer[0] == b'\n' => break,
+                Ok(_) => line.push(buffer[0]),
+                Err(_) => return Err(VmErrorKind::SysError(SysError::InvalidCharacter)),
+            }
+        }
 
-                        self.push_stack(a.saturating_sub(b))?;
-                    }
-                    OpCode::MulI => {
-                        let b: u8 = self.next_byte()?;
-                        let a: u8 = self.pop_stack()?;
+        let input = String::from_utf8_lossy(&line).to_string();
+        match input.trim().parse::<i128>() {
+            Ok(number) if C::value_range().contains(&number) => self.push_stack(C::wrap(number)),
+            _ => Err(VmErrorKind::SysError(SysError::InvalidNumber(input))),
+        }
+    }
 
-                        self.push_stack(a.saturating_mul(b))?;
-                    }
-                    OpCode::DivI => {
-                        let b: u8 = self.next_byte()?;
-                        let a: u8 = self.pop_stack()?;
+    // addr 番地の長さ1セル + データの文字列を読み出す (1セルに1バイト)
+    pub fn load_str(&self, addr: usize) -> Result<Vec<u8>, VmErrorKind> {
+        let len = self.load_memory(addr)?.value().max(0) as usize;
+        // 長さはプログラムが書いた値なので、確保する前にメモリに収まるか確かめる
+        // 収まらなければ、文字列のうち最初にメモリの外になる番地をエラーにする
+        let end = addr.saturating_add(1).saturating_add(len);
+        if self.memory.len() < end {
+            return Err(VmErrorKind::InvalidMemoryAccess(self.memory.len().min(end)));
+        }
 
-                        if b == 0 {
-                            return Err(VmError::DivisionByZero);
-                        }
+        let mut text: Vec<u8> = Vec::with_capacity(len);
+        for i in 0..len {
+            text.push(self.load_memory(addr + 1 + i)?.to_bits() as u8);
+        }
 
-                        self.push_stack(a.saturating_div(b))?;
-                    }
-                    OpCode::ModI => {
-                        let b: u8 = self.next_byte()?;
-                        let a: u8 = self.pop_stack()?;
+        Ok(text)
+    }
 
-                        self.push_stack(a % b)?;
-                    }
-                    OpCode::Eq => {
-                        let b: u8 = self.pop_stack()?;
-                        let a: u8 = self.pop_stack()?;
+    // addr 番地に長さ1セル + データの文字列を書き込む
+    pub fn store_str(&mut self, text: &[u8], addr: usize) -> Result<(), VmErrorKind> {
+        self.store_memory(self.cell_from_len(text.len())?, addr)?;
+        for (i, c) in text.iter().enumerate() {
+            self.store_memory(C::from_u8(*c), addr + 1 + i)?;
+        }
 
-                        self.push_stack(if a == b { 0 } else { 1 })?;
-                    }
-                    OpCode::Neq => {
-                        let b: u8 = self.pop_stack()?;
-                        let a: u8 = self.pop_stack()?;
+        Ok(())
+    }
 
-                        self.push_stack(if a != b { 0 } else { 1 })?;
-                    }
-                    OpCode::Lt => {
-                        let b: u8 = self.pop_stack()?;
-                        let a: u8 = self.pop_stack()?;
+    // 個数や長さをセルにする
+    fn cell_from_len(&self, len: usize) -> Result<C, VmErrorKind> {
+        if !C::value_range().contains(&(len as i128)) {
+            return Err(VmErrorKind::SysError(SysError::ValueTooLarge(len)));
+        }
 
-                        self.push_stack(if a < b { 0 } else { 1 })?;
-                    }
-                    OpCode::Le => {
-                        let b: u8 = self.pop_stack()?;
-                        let a: u8 = self.pop_stack()?;
+        Ok(C::wrap(len as i128))
+    }
 
-                        self.push_stack(if a <= b { 0 } else { 1 })?;
-                    }
-                    OpCode::Gt => {
-                        let b: u8 = self.pop_stack()?;
-                        let a: u8 = self.pop_stack()?;
+    // addr 番地の長さ1セル + データの文字列を出力する
+    pub fn sys_print_str(&mut self) -> Result<(), VmErrorKind> {
+        let addr = self.pop_addr()?;
+        let text = self.load_str(addr)?;
 
-                        self.push_stack(if a > b { 0 } else { 1 })?;
-                    }
-                    OpCode::Ge => {
-                        let b: u8 = self.pop_stack()?;
-                        let a: u8 = self.pop_stack()?;
+        self.write_out(&text.iter().map(|c| *c as char).collect::<String>())
+    }
 
-                        self.push_stack(if a >= b { 0 } else { 1 })?;
-                    }
-                    OpCode::Jz => {
-                        let flg = self.pop_stack()?;
-                        let dst = self.next_byte()?;
-                        if flg == 0 {
-                            self.pc = dst as usize;
-                            continue;
-                        }
-                    }
-                    OpCode::Jmz => {
-                        let dst = self.next_byte()?;
-                        self.pc = dst as usize;
-                        continue;
-                    }
-                    OpCode::Store => {
-                        let mem_dst = self.pop_stack()? as usize;
-                        let target = self.pop_stack()?;
-                        self.store_memory(target, mem_dst)?;
-                    }
-                    OpCode::Load => {
-                        let mem_dst = self.pop_stack()? as usize;
-                        let target = self.load_memory(mem_dst)?.unwrap();
-                        self.push_stack(target)?;
-                    }
-                    OpCode::StoreI => {
-                        let mem_dst = self.next_byte()? as usize;
-                        let target = self.pop_stack()?;
-                        self.store_memory(target, mem_dst)?;
-                    }
-                    OpCode::LoadI => {
-                        let mem_dst = self.next_byte()? as usize;
-                        let target = self.load_memory(mem_dst)?.unwrap();
-                        self.push_stack(target)?;
-                    }
-                    OpCode::Call => {
-                        let dst = self.next_byte()? as usize;
-                        self.push_call(self.pc)?;
-                        self.pc = dst;
+    // addr 番地から 0 までの文字列を出力する
+    pub fn sys_print_cstr(&mut self) -> Result<(), VmErrorKind> {
+        let addr = self.pop_addr()?;
 
-                        continue;
-                    }
-                    OpCode::Ret => {
-                        let dst = self.pop_call()?;
-                        self.pc = dst;
-                    }
-                    OpCode::Fin => {
-                        self.halted = true;
-                        self.exit_code = 0;
-                    }
-                }
-            } else {
-                return Err(VmError::InvalidOpcode(token));
+        let mut text: Vec<u8> = Vec::new();
+        for dst in addr.. {
+            match self.load_memory(dst)?.to_bits() {
+                0 => break,
+                c => text.push(c as u8),
             }
+        }
+
+        self.write_out(&text.iter().map(|c| *c as char).collect::<String>())
+    }
 
-            self.pc += 1;
+    // n をポップし、0..n の乱数をプッシュする (n <= 0 ならセル全体から)
+    pub fn sys_random(&mut self) -> Result<(), VmErrorKind> {
+        let bound = self.pop_stack()?.value().max(0);
+
+        self.record(Change::Rng(self.rng.state));
+        let value = C::from_bits(self.rng.below(bound as u64));
+
+        self.push_stack(value)
+    }
+
+    // 開始からの経過ミリ秒 (u32) をセルの幅に区切って上位から順にプッシュする
+    // (8bit セルでは4つ、スタックトップが最下位バイト。32bit 以上のセルでは1つ)
+    pub fn sys_time(&mut self) -> Result<(), VmErrorKind> {
+        let millis = self.clock.now().as_millis() as u32;
+
+        for chunk in millis.to_be_bytes().chunks(C::WIDTH.bytes().min(4)) {
+            let bits = chunk.iter().fold(0, |acc, byte| acc << 8 | *byte as u64);
+            self.push_stack(C::from_bits(bits))?;
         }
 
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::opcode::OpCode;
+    // ms をポップし、その時間だけ停止する
+    pub fn sys_sleep(&mut self) -> Result<(), VmErrorKind> {
+        let millis = self.pop_stack()?.value().max(0);
 
-    fn run_vm(tokens: Vec<u8>) -> VM<std::io::Empty, std::io::Sink> {
-        let mut vm = VM::new(tokens, std::io::empty(), std::io::sink());
-        vm.run().unwrap();
-        vm
+        self.clock.sleep(Duration::from_millis(millis as u64));
+
+        Ok(())
     }
 
-    #[test]
-    fn test_add() {
-        let code = vec![
-            OpCode::Push as u8,
-            10,
-            OpCode::Push as u8,
-            20,
-            OpCode::Add as u8,
-        ];
-        let mut vm = run_vm(code);
-        assert_eq!(vm.stack.pop(), Some(30));
+    pub fn sys_argc(&mut self) -> Result<(), VmErrorKind> {
+        let argc = self.cell_from_len(self.args.len())?;
+
+        self.push_stack(argc)
     }
 
-    #[test]
-    fn test_stack_underflow() {
-        // 空のスタックからPOPしようとする
-        let code = vec![OpCode::Pop as u8];
-        let mut vm = VM::new(code, std::io::empty(), std::io::sink());
+    // (Stack: n, addr) n 番目の引数を addr 番地に長さ付き文字列としてコピーする
+    pub fn sys_argv(&mut self) -> Result<(), VmErrorKind> {
+        let addr = self.pop_addr()?;
+        let n = self.pop_stack()?.to_bits();
 
-        // エラーになるべき
-        match vm.run() {
-            Err(VmError::StackUnderflow) => (), // OK
-            _ => panic!("Expected StackUnderflow error"),
+        let arg = match self.args.get(n as usize) {
+            Some(arg) => arg.clone(),
+            None => return Err(VmErrorKind::SysError(SysError::NoSuchArgument(n))),
+        };
+
+        self.store_str(arg.as_bytes(), addr)
+    }
+
+    // (Stack: name, addr) name 番地の長さ付き文字列を名前とする環境変数を addr 番地にコピーする
+    // 見つかれば 0、許可されていない・存在しない場合は 1 をプッシュする
+    pub fn sys_getenv(&mut self) -> Result<(), VmErrorKind> {
+        let addr = self.pop_addr()?;
+        let name = self.pop_addr()?;
+
+        let name = String::from_utf8_lossy(&self.load_str(name)?).to_string();
+        match self.env.get(&name).cloned() {
+            Some(value) => {
+                self.store_str(value.as_bytes(), addr)?;
+                self.push_stack(C::from_u8(0))
+            }
+            None => self.push_stack(C::from_u8(1)),
         }
     }
 
-    #[test]
-    fn test_jz_jump() {
-        // 条件ジャンプのテスト
-        // PUSH 0, JZ 0x06, PUSH 1(Skip), FIN, PUSH 2(Target), FIN
-        let code = vec![
-            OpCode::Push as u8,
-            0,
-            OpCode::Jz as u8,
-            0x07,
-            OpCode::Push as u8,
-            1, // ここは実行されないはず
-            OpCode::Fin as u8,
-            OpCode::Push as u8,
-            2, // ここに飛んでくるはず
-            OpCode::Fin as u8,
-        ];
-        let mut vm = run_vm(code);
+    fn file(&mut self, handle: u64) -> Result<&mut OpenFile, VmErrorKind> {
+        match self.files.get_mut(handle as usize) {
+            Some(Some(file)) => Ok(file),
+            _ => Err(VmErrorKind::SysError(SysError::InvalidHandle(handle))),
+        }
+    }
 
-        // PUSH 2 だけが実行されているはず
-        assert_eq!(vm.stack.pop(), Some(2));
+    // (Stack: path, mode) path 番地の長さ付き文字列のファイルを開き、ハンドルをプッシュする
+    pub fn sys_open(&mut self) -> Result<(), VmErrorKind> {
+        let mode = self.pop_stack()?.to_bits();
+        let path = self.pop_addr()?;
+
+        let mode = u8::try_from(mode)
+            .ok()
+            .and_then(OpenMode::from_u8)
+            .ok_or(VmErrorKind::SysError(SysError::InvalidOpenMode(mode)))?;
+        let path = String::from_utf8_lossy(&self.load_str(path)?).to_string();
+
+        let handle = match self.files.iter().position(|f| f.is_none()) {
+            Some(handle) => handle,
+            None if self.files.len() < FILES_SIZE => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(VmErrorKind::SysError(SysError::TooManyFiles)),
+        };
+
+        let fs = self
+            .fs
+            .as_mut()
+            .ok_or(VmErrorKind::SysError(SysError::PermissionDenied(
+                path.clone(),
+            )))?;
+        let file = fs.open(&path, mode).map_err(VmErrorKind::SysError)?;
+        self.files[handle] = Some(OpenFile { handle: file, mode });
+
+        self.push_stack(C::from_bits(handle as u64))
     }
 
-    #[test]
-    fn test_div_by_zero() {
-        // 10 / 0
-        let code = vec![
-            OpCode::Push as u8,
-            10,
-            OpCode::Push as u8,
-            0,
-            OpCode::Div as u8,
-        ];
-        let mut vm = VM::new(code, std::io::empty(), std::io::sink());
+    // (Stack: handle) 1バイト読み、値と状態 (0: 成功, 1: EOF) をプッシュする
+    pub fn sys_fread(&mut self) -> Result<(), VmErrorKind> {
+        let handle = self.pop_stack()?.to_bits();
+        let file = self.file(handle)?;
+        if file.mode != OpenMode::Read {
+            return Err(VmErrorKind::SysError(SysError::InvalidHandle(handle)));
+        }
 
-        match vm.run() {
-            Err(VmError::DivisionByZero) => (), // OK
-            _ => panic!("Expected DivisionByZero error"),
+        let mut buffer = [0u8; 1];
+        match file.handle.read(&mut buffer) {
+            Ok(0) => {
+                self.push_stack(C::from_u8(0))?;
+                self.push_stack(C::from_u8(1))
+            }
+            Ok(_) => {
+                self.push_stack(C::from_u8(buffer[0]))?;
+                self.push_stack(C::from_u8(0))
+            }
+            Err(e) => Err(VmErrorKind::SysError(SysError::Io(e.to_string()))),
         }
     }
+
+    // (Stack: val, handle) val の下位1バイトを書き込む
+    pub fn sys_fwrite(&mut self) -> Result<(), VmErrorKind> {
+        let handle = self.pop_stack()?.to_bits();
+        let val = self.pop_stack()?.to_bits() as u8;
+        let file = self.file(handle)?;
+        if file.mode == OpenMode::Read {
+            return Err(VmErrorKind::SysError(SysError::InvalidHandle(handle)));
+        }
+
+        file.handle
+            .write_all(&[val])
+            .map_err(|e| VmErrorKind::SysError(SysError::Io(e.to_string())))
+    }
+
+    // (Stack: handle) ファイルを閉じる
+    pub fn sys_close(&mut self) -> Result<(), VmErrorKind> {
+        let handle = self.pop_stack()?.to_bits();
+        let mut file = match self.files.get_mut(handle as usize).and_then(|f| f.take()) {
+            Some(file) => file,
+            None => return Err(VmErrorKind::SysError(SysError::InvalidHandle(handle))),
+        };
+
+        file.handle
+            .flush()
+            .map_err(|e| VmErrorKind::SysError(SysError::Io(e.to_string())))
+    }
+
+    pub fn sys_dump(&mut self) -> Result<(), VmErrorKind> {
+        let text = self.dump(self.dump_format);
+
+        self.debug_port
+            .write_all(text.as_bytes())
+            .and_then(|_| self.debug_port.flush())
+            .map_err(|e| VmErrorKind::SysError(SysError::Io(e.to_string())))
+    }
+
+    pub fn sys_exit(&mut self) -> Result<(), VmErrorKind> {
+        let code = self.pop_stack()?.to_bits() as u8;
+        self.exit_code = code;
+        self.halted = true;
+
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.halted || self.tokens.len() <= self.pc
+    }
+
+    pub fn run(&mut self) -> Result<(), VmError> {
+        while !self.is_finished() {
+            self.step()?;
+        }
+
+        Ok(())
+    }
+
+    // 最大 steps 命令だけ実行する (終了していなければ途中で戻る)
+    pub fn run_for(&mut self, steps: u64) -> Result<(), VmError> {
+        for _ in 0..steps {
+            if self.is_finished() {
+                break;
+            }
+            self.step()?;
+        }
+
+        Ok(())
+    }
+
+    fn record_branch(&mut self, addr: usize, taken: bool) {
+        if let Some(coverage) = &mut self.coverage {
+            coverage.branch(addr, taken);
+        }
+    }
+
+    // 1命令を実行する (エラーには失敗した命令の位置とその時点の状態を付ける)
+    // 終了済みなら何もしない
+    pub fn step(&mut self) -> Result<(), VmError> {
+        if self.is_finished() {
+            return Ok(());
+        }
+
+        let pc = self.pc;
+        let depth = self.stack.len();
+        let stack_top = self.stack.last().map(|val| val.value());
+        let call_depth = self.call.len();
+
+        self.execute().map_err(|kind| VmError {
+            kind,
+            pc,
+            opcode: self
+                .tokens
+                .get(pc)
+                .and_then(|&token| OpCode::from_u8(token)),
+            depth,
+            stack_top,
+            call_depth,
+        })
+    }
+
+    fn execute(&mut self) -> Result<(), VmErrorKind> {
+        let pc = self.pc;
+        self.last_pc = pc;
+
+        if let Some(profile) = &mut self.profile {
+            let call = self
+                .call
+                .iter()
+                .map(|frame| frame.entry)
+                .collect::<Vec<usize>>();
+            profile.record(&call);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.hit(pc);
+        }
+        if let Some(history) = &mut self.history {
+            history.begin(pc, self.halted, self.exit_code);
+        }
+
+        let token = self.tokens[self.pc];
+
+        if let Some(opcode) = OpCode::from_u8(token) {
+            match opcode {
+                OpCode::SysCall => {
+                    let n = self.pop_stack()?.to_bits();
+                    let handler = match u8::try_from(n).ok().and_then(|n| self.syscalls.get(&n)) {
+                        Some(handler) => handler.clone(),
+                        None => return Err(VmErrorKind::UnexpectedSysCall(n)),
+                    };
+                    handler.call(self)?;
+                }
+                OpCode::Push => {
+                    let val = self.next_cell()?;
+                    self.push_stack(val)?;
+                }
+                OpCode::Pop => {
+                    self.pop_stack()?;
+                }
+                OpCode::Dup => {
+                    let a = self.pop_stack()?;
+                    self.push_stack(a)?;
+                    self.push_stack(a)?;
+                }
+                OpCode::Swap => {
+                    let a = self.pop_stack()?;
+                    let b = self.pop_stack()?;
+
+                    self.push_stack(a)?;
+                    self.push_stack(b)?;
+                }
+                OpCode::Over => {
+                    self.check_depth(2)?;
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(a)?;
+                    self.push_stack(b)?;
+                    self.push_stack(a)?;
+                }
+                OpCode::Rot => {
+                    self.check_depth(3)?;
+                    let c = self.pop_stack()?;
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(b)?;
+                    self.push_stack(c)?;
+                    self.push_stack(a)?;
+                }
+                OpCode::NRot => {
+                    self.check_depth(3)?;
+                    let c = self.pop_stack()?;
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(c)?;
+                    self.push_stack(a)?;
+                    self.push_stack(b)?;
+                }
+                OpCode::Nip => {
+                    self.check_depth(2)?;
+                    let b = self.pop_stack()?;
+                    self.pop_stack()?;
+
+                    self.push_stack(b)?;
+                }
+                OpCode::Tuck => {
+                    self.check_depth(2)?;
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(b)?;
+                    self.push_stack(a)?;
+                    self.push_stack(b)?;
+                }
+                OpCode::Pick => {
+                    // 上から n 番目 (0 = スタックトップ) を複製する
+                    let n = self.next_byte()? as usize;
+                    self.check_depth(n + 1)?;
+
+                    let val = self.stack[self.stack.len() - 1 - n];
+                    self.push_stack(val)?;
+                }
+                OpCode::Roll => {
+                    // 上から n 番目 (0 = スタックトップ) をスタックトップへ移動する
+                    let n = self.next_byte()? as usize;
+                    self.check_depth(n + 1)?;
+
+                    let mut items = Vec::with_capacity(n + 1);
+                    for _ in 0..=n {
+                        items.push(self.pop_stack()?);
+                    }
+                    let target = items.pop().unwrap();
+                    for val in items.into_iter().rev() {
+                        self.push_stack(val)?;
+                    }
+                    self.push_stack(target)?;
+                }
+                OpCode::DropN => {
+                    let n = self.next_byte()? as usize;
+                    self.check_depth(n)?;
+
+                    for _ in 0..n {
+                        self.pop_stack()?;
+                    }
+                }
+                OpCode::Depth => {
+                    let depth = self.stack.len() as i128;
+                    if !C::value_range().contains(&depth) {
+                        return Err(VmErrorKind::StackOverflow);
+                    }
+
+                    self.push_stack(C::wrap(depth))?;
+                }
+                OpCode::Add => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    let val = self.overflow(a.value() + b.value(), C::value_range())?;
+                    self.set_flags(Flags::add(a, b, false, val));
+                    self.push_stack(val)?;
+                }
+                OpCode::Sub => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    let val = self.overflow(a.value() - b.value(), C::value_range())?;
+                    self.set_flags(Flags::sub(a, b, false, val));
+                    self.push_stack(val)?;
+                }
+                OpCode::Mul => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    let val = self.overflow(a.value() * b.value(), C::value_range())?;
+                    self.set_flags(Flags::mul(a, b, val));
+                    self.push_stack(val)?;
+                }
+                OpCode::Div => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    if b.to_bits() == 0 {
+                        return Err(VmErrorKind::DivisionByZero);
+                    }
+
+                    let n = a.value() / b.value();
+                    let val = self.overflow(n, C::value_range())?;
+                    self.set_flags(Flags::new(val, false, !C::value_range().contains(&n)));
+                    self.push_stack(val)?;
+                }
+                OpCode::Mod => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    if b.to_bits() == 0 {
+                        return Err(VmErrorKind::DivisionByZero);
+                    }
+
+                    let val = C::wrap(a.value() % b.value());
+                    self.set_flags(Flags::new(val, false, false));
+                    self.push_stack(val)?;
+                }
+                OpCode::AddI => {
+                    let b = self.next_cell()?;
+                    let a = self.pop_stack()?;
+
+                    let val = self.overflow(a.value() + b.value(), C::value_range())?;
+                    self.set_flags(Flags::add(a, b, false, val));
+                    self.push_stack(val)?;
+                }
+                OpCode::SubI => {
+                    let b = self.next_cell()?;
+                    let a = self.pop_stack()?;
+
+                    let val = self.overflow(a.value() - b.value(), C::value_range())?;
+                    self.set_flags(Flags::sub(a, b, false, val));
+                    self.push_stack(val)?;
+                }
+                OpCode::MulI => {
+                    let b = self.next_cell()?;
+                    let a = self.pop_stack()?;
+
+                    let val = self.overflow(a.value() * b.value(), C::value_range())?;
+                    self.set_flags(Flags::mul(a, b, val));
+                    self.push_stack(val)?;
+                }
+                OpCode::DivI => {
+                    let b = self.next_cell()?;
+                    let a = self.pop_stack()?;
+
+                    if b.to_bits() == 0 {
+                        return Err(VmErrorKind::DivisionByZero);
+                    }
+
+                    let n = a.value() / b.value();
+                    let val = self.overflow(n, C::value_range())?;
+                    self.set_flags(Flags::new(val, false, !C::value_range().contains(&n)));
+                    self.push_stack(val)?;
+                }
+                OpCode::ModI => {
+                    let b = self.next_cell()?;
+                    let a = self.pop_stack()?;
+
+                    if b.to_bits() == 0 {
+                        return Err(VmErrorKind::DivisionByZero);
+                    }
+
+                    let val = C::wrap(a.value() % b.value());
+                    self.set_flags(Flags::new(val, false, false));
+                    self.push_stack(val)?;
+                }
+                OpCode::Eq => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_cond(a == b)?;
+                }
+                OpCode::Neq => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_cond(a != b)?;
+                }
+                OpCode::Lt => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_cond(a < b)?;
+                }
+                OpCode::Le => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_cond(a <= b)?;
+                }
+                OpCode::Gt => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_cond(a > b)?;
+                }
+                OpCode::Ge => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_cond(a >= b)?;
+                }
+                OpCode::Jz => {
+                    let flg = self.pop_stack()?;
+                    let dst = self.next_addr()?;
+                    self.record_branch(pc, flg.to_bits() == 0);
+                    if flg.to_bits() == 0 {
+                        self.pc = dst;
+                        return Ok(());
+                    }
+                }
+                OpCode::Jnz => {
+                    let flg = self.pop_stack()?;
+                    let dst = self.next_addr()?;
+                    self.record_branch(pc, flg.to_bits() != 0);
+                    if flg.to_bits() != 0 {
+                        self.pc = dst;
+                        return Ok(());
+                    }
+                }
+                OpCode::Jeq | OpCode::Jlt | OpCode::Jgt => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+                    let dst = self.next_addr()?;
+                    let taken = match opcode {
+                        OpCode::Jeq => a == b,
+                        OpCode::Jlt => a < b,
+                        _ => a > b,
+                    };
+                    self.record_branch(pc, taken);
+                    if taken {
+                        self.pc = dst;
+                        return Ok(());
+                    }
+                }
+                OpCode::Jc | OpCode::Jnc | OpCode::Jo => {
+                    let dst = self.next_addr()?;
+                    let taken = match opcode {
+                        OpCode::Jc => self.flags.carry,
+                        OpCode::Jnc => !self.flags.carry,
+                        _ => self.flags.overflow,
+                    };
+                    self.record_branch(pc, taken);
+                    if taken {
+                        self.pc = dst;
+                        return Ok(());
+                    }
+                }
+                OpCode::Jmz => {
+                    let dst = self.next_addr()?;
+                    self.pc = dst;
+                    return Ok(());
+                }
+                OpCode::JmpS => {
+                    let dst = self.pop_addr()?;
+                    self.pc = dst;
+                    return Ok(());
+                }
+                OpCode::Store => {
+                    let mem_dst = self.pop_addr()?;
+                    let target = self.pop_stack()?;
+                    self.store_memory(target, mem_dst)?;
+                }
+                OpCode::Load => {
+                    let mem_dst = self.pop_addr()?;
+                    let target = self.load_memory(mem_dst)?;
+                    self.push_stack(target)?;
+                }
+                OpCode::StoreI => {
+                    let mem_dst = self.next_addr()?;
+                    let target = self.pop_stack()?;
+                    self.store_memory(target, mem_dst)?;
+                }
+                OpCode::LoadI => {
+                    let mem_dst = self.next_addr()?;
+                    let target = self.load_memory(mem_dst)?;
+                    self.push_stack(target)?;
+                }
+                OpCode::LoadL => {
+                    let k = self.next_byte()? as i8;
+                    let idx = self.local(k)?;
+                    self.push_stack(self.stack[idx])?;
+                }
+                OpCode::StoreL => {
+                    let k = self.next_byte()? as i8;
+                    let target = self.pop_stack()?;
+                    let idx = self.local(k)?;
+                    self.record(Change::Local(idx, self.stack[idx]));
+                    self.stack[idx] = target;
+                }
+                OpCode::Call => {
+                    let dst = self.next_addr()?;
+                    self.push_call(self.pc, dst)?;
+                    self.pc = dst;
+
+                    return Ok(());
+                }
+                OpCode::CallS => {
+                    let dst = self.pop_addr()?;
+                    self.push_call(self.pc, dst)?;
+                    self.pc = dst;
+
+                    return Ok(());
+                }
+                OpCode::Ret => {
+                    let frame = self.pop_call()?;
+                    self.set_fp(frame.fp);
+                    self.pc = frame.ret;
+                }
+                OpCode::Enter => {
+                    // ここから上をこのフレームとし、ローカル変数 n 個を 0 で確保する
+                    let n = self.next_byte()?;
+                    self.set_fp(self.stack.len());
+                    for _ in 0..n {
+                        self.push_stack(C::default())?;
+                    }
+                }
+                OpCode::Leave => {
+                    // ローカル変数とその上に積んだ値を捨てる (フレームポインタは RET で戻る)
+                    while self.fp < self.stack.len() {
+                        self.pop_stack()?;
+                    }
+                }
+                OpCode::And => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(C::from_bits(a.to_bits() & b.to_bits()))?;
+                }
+                OpCode::Or => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(C::from_bits(a.to_bits() | b.to_bits()))?;
+                }
+                OpCode::Xor => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(C::from_bits(a.to_bits() ^ b.to_bits()))?;
+                }
+                OpCode::Not => {
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(C::from_bits(!a.to_bits()))?;
+                }
+                OpCode::Shl => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(shl(a, b.to_bits()))?;
+                }
+                OpCode::Shr => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(shr(a, b.to_bits()))?;
+                }
+                OpCode::AndI => {
+                    let b = self.next_cell()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(C::from_bits(a.to_bits() & b.to_bits()))?;
+                }
+                OpCode::OrI => {
+                    let b = self.next_cell()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(C::from_bits(a.to_bits() | b.to_bits()))?;
+                }
+                OpCode::XorI => {
+                    let b = self.next_cell()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(C::from_bits(a.to_bits() ^ b.to_bits()))?;
+                }
+                OpCode::ShlI => {
+                    let b = self.next_byte()? as u64;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(shl(a, b))?;
+                }
+                OpCode::ShrI => {
+                    let b = self.next_byte()? as u64;
+                    let a = self.pop_stack()?;
+
+                    self.push_stack(shr(a, b))?;
+                }
+                OpCode::SAdd => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    let val = self.overflow(a.signed() + b.signed(), C::signed_range())?;
+                    self.set_flags(Flags::add(a, b, false, val));
+                    self.push_stack(val)?;
+                }
+                OpCode::SSub => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    let val = self.overflow(a.signed() - b.signed(), C::signed_range())?;
+                    self.set_flags(Flags::sub(a, b, false, val));
+                    self.push_stack(val)?;
+                }
+                OpCode::SMul => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    let val = self.overflow(a.signed() * b.signed(), C::signed_range())?;
+                    self.set_flags(Flags::mul(a, b, val));
+                    self.push_stack(val)?;
+                }
+                OpCode::SDiv => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    if b.to_bits() == 0 {
+                        return Err(VmErrorKind::DivisionByZero);
+                    }
+
+                    let n = a.signed() / b.signed();
+                    let val = self.overflow(n, C::signed_range())?;
+                    self.set_flags(Flags::new(val, false, !C::signed_range().contains(&n)));
+                    self.push_stack(val)?;
+                }
+                OpCode::SMod => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    if b.to_bits() == 0 {
+                        return Err(VmErrorKind::DivisionByZero);
+                    }
+
+                    let val = C::wrap(a.signed() % b.signed());
+                    self.set_flags(Flags::new(val, false, false));
+                    self.push_stack(val)?;
+                }
+                OpCode::SLt => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_cond(a.signed() < b.signed())?;
+                }
+                OpCode::SGt => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+
+                    self.push_cond(a.signed() > b.signed())?;
+                }
+                OpCode::Neg => {
+                    let a = self.pop_stack()?;
+
+                    let val = self.overflow(-a.signed(), C::signed_range())?;
+                    self.set_flags(Flags::sub(C::default(), a, false, val));
+                    self.push_stack(val)?;
+                }
+                OpCode::Adc => {
+                    // 多倍長演算用: ArithMode に関係なく常に桁あふれした分を捨てる
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+                    let carry = self.flags.carry;
+
+                    let val = C::wrap(a.unsigned() + b.unsigned() + carry as i128);
+                    self.set_flags(Flags::add(a, b, carry, val));
+                    self.push_stack(val)?;
+                }
+                OpCode::Sbc => {
+                    let b = self.pop_stack()?;
+                    let a = self.pop_stack()?;
+                    let borrow = self.flags.carry;
+
+                    let val = C::wrap(a.unsigned() - b.unsigned() - borrow as i128);
+                    self.set_flags(Flags::sub(a, b, borrow, val));
+                    self.push_stack(val)?;
+                }
+                OpCode::Clc => {
+                    let flags = Flags {
+                        carry: false,
+                        ..self.flags
+                    };
+                    self.set_flags(flags);
+                }
+                OpCode::Fin => {
+                    self.halted = true;
+                    self.exit_code = 0;
+                }
+            }
+        } else {
+            return Err(VmErrorKind::InvalidOpcode(token));
+        }
+
+        self.pc += 1;
+
+        Ok(())
+    }
+}
+
+// セルの幅以上のシフトは 0 になる (SHR は符号を考えない論理シフト)
+fn shl<C: Cell>(a: C, b: u64) -> C {
+    if b >= C::bits() as u64 {
+        return C::default();
+    }
+
+    C::from_bits(a.to_bits() << b)
+}
+
+fn shr<C: Cell>(a: C, b: u64) -> C {
+    if b >= C::bits() as u64 {
+        return C::default();
+    }
+
+    C::from_bits(a.to_bits() >> b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::fs::MemoryFileSystem;
+    use crate::opcode::OpCode;
+
+    fn program(code: Vec<u8>) -> Vec<u8> {
+        let mut tokens = vec![0x00, b'T', b'W', b'N'];
+        tokens.extend(code);
+        tokens
+    }
+
+    fn run_vm(tokens: Vec<u8>) -> VM<std::io::Empty, std::io::Sink> {
+        let mut vm = VM::new(program(tokens), std::io::empty(), std::io::sink());
+        vm.run().unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_add() {
+        let code = vec![
+            OpCode::Push as u8,
+            10,
+            OpCode::Push as u8,
+            20,
+            OpCode::Add as u8,
+        ];
+        let mut vm = run_vm(code);
+        assert_eq!(vm.stack.pop(), Some(30));
+    }
+
+    #[test]
+    fn test_step_after_finish() {
+        // 終了した後の step は何もしない
+        let code = vec![OpCode::Push as u8, 30, OpCode::Fin as u8];
+        let mut vm = run_vm(code);
+        let pc = vm.pc;
+        vm.step().unwrap();
+        assert_eq!(vm.pc, pc);
+        assert_eq!(vm.stack, vec![30]);
+    }
+
+    #[test]
+    fn test_bitwise() {
+        let cases = [
+            (OpCode::And, 0b1100, 0b1010, 0b1000),
+            (OpCode::Or, 0b1100, 0b1010, 0b1110),
+            (OpCode::Xor, 0b1100, 0b1010, 0b0110),
+            (OpCode::Shl, 0x81, 1, 0x02),
+            (OpCode::Shr, 0x81, 7, 0x01),
+            (OpCode::Shl, 0xFF, 8, 0x00),
+        ];
+        for (opcode, a, b, expected) in cases {
+            let mut vm = run_vm(vec![
+                OpCode::Push as u8,
+                a,
+                OpCode::Push as u8,
+                b,
+                opcode as u8,
+            ]);
+            assert_eq!(vm.stack.pop(), Some(expected), "{:?}", opcode);
+        }
+
+        // 即値版と NOT: ((0xF0 | 0x0F) ^ 0x3C) & 0x77 = 0x43, NOT -> 0xBC, SHRI 2, SHLI 4
+        let code = vec![
+            OpCode::Push as u8,
+            0xF0,
+            OpCode::OrI as u8,
+            0x0F,
+            OpCode::XorI as u8,
+            0x3C,
+            OpCode::AndI as u8,
+            0x77,
+            OpCode::Dup as u8,
+            OpCode::Not as u8,
+            OpCode::ShrI as u8,
+            2,
+            OpCode::ShlI as u8,
+            4,
+        ];
+        let vm = run_vm(code);
+        assert_eq!(vm.stack, vec![0x43, 0xF0]);
+    }
+
+    #[test]
+    fn test_stack_words() {
+        let push = |vals: &[u8]| {
+            vals.iter()
+                .flat_map(|val| [OpCode::Push as u8, *val])
+                .collect::<Vec<u8>>()
+        };
+        let cases: Vec<(Vec<u8>, Vec<u8>)> = vec![
+            (vec![OpCode::Over as u8], vec![1, 2, 3, 2]),
+            (vec![OpCode::Rot as u8], vec![2, 3, 1]),
+            (vec![OpCode::NRot as u8], vec![3, 1, 2]),
+            (vec![OpCode::Nip as u8], vec![1, 3]),
+            (vec![OpCode::Tuck as u8], vec![1, 3, 2, 3]),
+            (vec![OpCode::Pick as u8, 2], vec![1, 2, 3, 1]),
+            (vec![OpCode::Roll as u8, 2], vec![2, 3, 1]),
+            (vec![OpCode::Roll as u8, 0], vec![1, 2, 3]),
+            (vec![OpCode::DropN as u8, 2], vec![1]),
+            (vec![OpCode::Depth as u8], vec![1, 2, 3, 3]),
+        ];
+        for (op, expected) in cases {
+            let mut code = push(&[1, 2, 3]);
+            code.extend_from_slice(&op);
+            let vm = run_vm(code);
+            assert_eq!(vm.stack, expected, "{:?}", OpCode::from_u8(op[0]));
+        }
+
+        // 深さが足りなければスタックには触れずにエラー
+        for op in [
+            vec![OpCode::Pick as u8, 3],
+            vec![OpCode::Roll as u8, 3],
+            vec![OpCode::DropN as u8, 4],
+        ] {
+            let mut code = push(&[1, 2, 3]);
+            code.extend_from_slice(&op);
+            let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+            match vm.run() {
+                Err(VmError {
+                    kind: VmErrorKind::StackUnderflow,
+                    ..
+                }) => assert_eq!(vm.stack, vec![1, 2, 3]),
+                _ => panic!("Expected StackUnderflow error"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_signed() {
+        let cases: [(OpCode, i8, i8, i8); 10] = [
+            (OpCode::SAdd, -3, 5, 2),
+            (OpCode::SAdd, 100, 100, 127),
+            (OpCode::SSub, 3, 5, -2),
+            (OpCode::SSub, -100, 100, -128),
+            (OpCode::SMul, -4, 6, -24),
+            (OpCode::SDiv, -7, 2, -3),
+            (OpCode::SDiv, -128, -1, 127),
+            (OpCode::SMod, -7, 2, -1),
+            (OpCode::SLt, -1, 1, 0),
+            (OpCode::SGt, -1, 1, 1),
+        ];
+        for (opcode, a, b, expected) in cases {
+            let code = vec![
+                OpCode::Push as u8,
+                a as u8,
+                OpCode::Push as u8,
+                b as u8,
+                opcode as u8,
+            ];
+            let mut vm = run_vm(code);
+            assert_eq!(vm.stack.pop(), Some(expected as u8), "{:?}", opcode);
+        }
+
+        let mut vm = run_vm(vec![OpCode::Push as u8, 5, OpCode::Neg as u8]);
+        assert_eq!(vm.stack.pop(), Some(-5i8 as u8));
+
+        let code = vec![
+            OpCode::Push as u8,
+            0xF0,
+            OpCode::Push as u8,
+            0,
+            OpCode::SMod as u8,
+        ];
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+        match vm.run() {
+            Err(VmError {
+                kind: VmErrorKind::DivisionByZero,
+                ..
+            }) => (),
+            _ => panic!("Expected DivisionByZero error"),
+        }
+    }
+
+    #[test]
+    fn test_arith_mode() {
+        // 200 + 100, -100 - 100 (符号付き)
+        let unsigned = vec![OpCode::Push as u8, 200, OpCode::AddI as u8, 100];
+        let signed = vec![
+            OpCode::Push as u8,
+            0x9C,
+            OpCode::Push as u8,
+            100,
+            OpCode::SSub as u8,
+        ];
+
+        for (mode, expected) in [
+            (ArithMode::Saturate, Some((255, 0x80))),
+            (ArithMode::Wrap, Some((44, 0x38))),
+            (ArithMode::Trap, None),
+        ] {
+            let config = VmConfig {
+                arith_mode: mode,
+                ..Default::default()
+            };
+            let mut results = Vec::new();
+            for code in [unsigned.clone(), signed.clone()] {
+                let mut vm = VM::with_config(
+                    program(code),
+                    std::io::empty(),
+                    std::io::sink(),
+                    config.clone(),
+                );
+                match vm.run() {
+                    Ok(()) => results.push(vm.stack.pop().unwrap()),
+                    Err(VmError {
+                        kind: VmErrorKind::ArithmeticOverflow,
+                        ..
+                    }) => (),
+                    Err(e) => panic!("Unexpected error: {}", e),
+                }
+            }
+            assert_eq!(
+                results,
+                expected.map_or(vec![], |(a, b)| vec![a, b]),
+                "{:?}",
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn test_mod_by_zero() {
+        for code in [
+            vec![
+                OpCode::Push as u8,
+                7,
+                OpCode::Push as u8,
+                0,
+                OpCode::Mod as u8,
+            ],
+            vec![OpCode::Push as u8, 7, OpCode::ModI as u8, 0],
+        ] {
+            let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+            match vm.run() {
+                Err(VmError {
+                    kind: VmErrorKind::DivisionByZero,
+                    ..
+                }) => (),
+                _ => panic!("Expected DivisionByZero error"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_flags_multibyte_add() {
+        // 0x01FF + 0x0001 を下位バイトから ADC で計算し、上位バイトの後に JC/JO を試す
+        let code = vec![
+            OpCode::Clc as u8,
+            OpCode::Push as u8,
+            0xFF,
+            OpCode::Push as u8,
+            0x01,
+            OpCode::Adc as u8, // 下位 = 0x00, carry
+            OpCode::Push as u8,
+            0x01,
+            OpCode::Push as u8,
+            0x00,
+            OpCode::Adc as u8, // 上位 = 0x02
+            OpCode::Jc as u8,
+            0x11,
+            OpCode::Push as u8,
+            0x7F,
+            OpCode::AddI as u8,
+            1, // 符号付きでは 127 + 1 で overflow
+            OpCode::Fin as u8,
+        ];
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+        vm.history = Some(History::new(16));
+        vm.run().unwrap();
+        assert_eq!(vm.stack, vec![0x00, 0x02, 0x80]);
+        assert_eq!(
+            vm.flags,
+            Flags {
+                zero: false,
+                carry: false,
+                overflow: true,
+                negative: true,
+            }
+        );
+
+        // ADDI まで戻すと上位バイトの ADC 後のフラグに戻る
+        vm.step_back();
+        vm.step_back();
+        vm.step_back();
+        assert_eq!(vm.flags, Flags::new(0x02u8, false, false));
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        // 空のスタックからPOPしようとする
+        let code = vec![OpCode::Pop as u8];
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+
+        // エラーになるべき
+        match vm.run() {
+            Err(VmError {
+                kind: VmErrorKind::StackUnderflow,
+                ..
+            }) => (), // OK
+            _ => panic!("Expected StackUnderflow error"),
+        }
+    }
+
+    #[test]
+    fn test_jz_jump() {
+        // 条件ジャンプのテスト
+        // PUSH 0, JZ 0x06, PUSH 1(Skip), FIN, PUSH 2(Target), FIN
+        let code = vec![
+            OpCode::Push as u8,
+            0,
+            OpCode::Jz as u8,
+            0x07,
+            OpCode::Push as u8,
+            1, // ここは実行されないはず
+            OpCode::Fin as u8,
+            OpCode::Push as u8,
+            2, // ここに飛んでくるはず
+            OpCode::Fin as u8,
+        ];
+        let mut vm = run_vm(code);
+
+        // PUSH 2 だけが実行されているはず
+        assert_eq!(vm.stack.pop(), Some(2));
+    }
+
+    #[test]
+    fn test_conditional_jumps() {
+        // a, b を積んで分岐し、分岐したら 2、しなければ 1 を積む
+        let cases = [
+            (OpCode::Jnz, vec![3], 2),
+            (OpCode::Jnz, vec![0], 1),
+            (OpCode::Jeq, vec![5, 5], 2),
+            (OpCode::Jeq, vec![5, 6], 1),
+            (OpCode::Jlt, vec![5, 6], 2),
+            (OpCode::Jlt, vec![6, 5], 1),
+            (OpCode::Jgt, vec![6, 5], 2),
+            (OpCode::Jgt, vec![5, 5], 1),
+        ];
+        for (opcode, vals, expected) in cases {
+            let mut code = vals
+                .iter()
+                .flat_map(|val| [OpCode::Push as u8, *val])
+                .collect::<Vec<u8>>();
+            let target = code.len() as u8 + 5;
+            code.extend_from_slice(&[
+                opcode as u8,
+                target,
+                OpCode::Push as u8,
+                1,
+                OpCode::Fin as u8,
+                OpCode::Push as u8,
+                2,
+            ]);
+            let vm = run_vm(code);
+            assert_eq!(vm.stack, vec![expected], "{:?} {:?}", opcode, vals);
+        }
+    }
+
+    #[test]
+    fn test_indirect_jump_and_call() {
+        // メモリ 0x10 に置いたジャンプテーブルからサブルーチンを選んで呼ぶ
+        // (0x00) PUSH 0x0E, STOREI 0x10, LOADI 0x10, CALLS, PUSH 0x0D, JMPS, (0x0B) PUSH 1,
+        // (0x0D) FIN, (0x0E) PUSH 7, RET
+        let code = vec![
+            OpCode::Push as u8,
+            0x0E,
+            OpCode::StoreI as u8,
+            0x10,
+            OpCode::LoadI as u8,
+            0x10,
+            OpCode::CallS as u8,
+            OpCode::Push as u8,
+            0x0D,
+            OpCode::JmpS as u8,
+            OpCode::Push as u8,
+            1, // ここは実行されないはず
+            OpCode::Fin as u8,
+            OpCode::Fin as u8,
+            OpCode::Push as u8,
+            7,
+            OpCode::Ret as u8,
+        ];
+        let vm = run_vm(code);
+        assert_eq!(vm.stack, vec![7]);
+        assert!(vm.call.is_empty());
+    }
+
+    #[test]
+    fn test_wide_address() {
+        // (0x00) PUSH 7, STOREI 0x1234, JMZ 0x0108, (0x0108) LOADI 0x1234,
+        // PUSH 0x12, PUSH 0x35, STORE (7 を 0x1235 へ), FIN
+        let mut code = vec![0x00, b'T', b'W', b'E', 0x01];
+        code.extend_from_slice(&[
+            OpCode::Push as u8,
+            7,
+            OpCode::StoreI as u8,
+            0x34,
+            0x12,
+            OpCode::Jmz as u8,
+            0x08,
+            0x01,
+        ]);
+        code.resize(5 + 0x108, OpCode::Fin as u8);
+        code.extend_from_slice(&[
+            OpCode::LoadI as u8,
+            0x34,
+            0x12,
+            OpCode::Push as u8,
+            0x12,
+            OpCode::Push as u8,
+            0x35,
+            OpCode::Store as u8,
+            OpCode::Fin as u8,
+        ]);
+
+        let mut vm = VM::new(code, std::io::empty(), std::io::sink());
+        vm.run().unwrap();
+        assert!(vm.header.wide);
+        assert_eq!(vm.memory.len(), 0x10000);
+        assert_eq!(vm.memory[0x1234], Some(7));
+        assert_eq!(vm.memory[0x1235], Some(7));
+        assert!(vm.stack.is_empty());
+
+        // システムコールも 0xFF より上のアドレスを2セルで受け取る
+        let code = vec![
+            0x00,
+            b'T',
+            b'W',
+            b'E',
+            0x01,
+            OpCode::Push as u8,
+            0x12,
+            OpCode::Push as u8,
+            0x34,
+            OpCode::Push as u8,
+            syscall::SYS_PRINT_STR,
+            OpCode::SysCall as u8,
+        ];
+        let mut vm = VM::new(code, std::io::empty(), Vec::new());
+        vm.store_str(b"hi", 0x1234).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.out_port, b"hi");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_cell_width() {
+        // u16: PUSH 1000, PUSH 0x0040, MUL, DUP, ADDI 0xFFFF (飽和), PUSH 0x0010, STORE
+        let code = vec![
+            0x00,
+            b'T',
+            b'W',
+            b'E',
+            0x02,
+            OpCode::Push as u8,
+            0xE8,
+            0x03,
+            OpCode::Push as u8,
+            0x40,
+            0x00,
+            OpCode::Mul as u8,
+            OpCode::Dup as u8,
+            OpCode::AddI as u8,
+            0xFF,
+            0xFF,
+            OpCode::Push as u8,
+            0x10,
+            0x00,
+            OpCode::Store as u8,
+        ];
+        let mut vm = VM::<_, _, _, u16>::create(
+            code,
+            std::io::empty(),
+            std::io::sink(),
+            std::io::sink(),
+            VmConfig::default(),
+        );
+        vm.run().unwrap();
+        assert_eq!(vm.stack, vec![64000]);
+        assert_eq!(vm.memory[0x10], Some(u16::MAX));
+        assert!(vm.flags.carry);
+
+        // i64: PUSH -5, PUSH 3, DIV (-1), PUSH 0, LT (通常の除算と比較も符号付き)
+        let mut code = vec![0x00, b'T', b'W', b'E', 0x06, OpCode::Push as u8];
+        code.extend_from_slice(&(-5i64).to_le_bytes());
+        code.push(OpCode::Push as u8);
+        code.extend_from_slice(&3i64.to_le_bytes());
+        code.push(OpCode::Div as u8);
+        code.push(OpCode::Push as u8);
+        code.extend_from_slice(&0i64.to_le_bytes());
+        code.push(OpCode::Lt as u8);
+        let mut vm = VM::<_, _, _, i64>::create(
+            code,
+            std::io::empty(),
+            std::io::sink(),
+            std::io::sink(),
+            VmConfig::default(),
+        );
+        vm.run().unwrap();
+        assert_eq!(vm.stack, vec![0]);
+
+        let snapshot = vm.snapshot();
+        let resumed = VM::<_, _, _, i64>::from_snapshot_with_debug_port(
+            &snapshot,
+            std::io::empty(),
+            std::io::sink(),
+            std::io::sink(),
+        )
+        .unwrap();
+        assert_eq!(resumed.snapshot(), snapshot);
+        assert!(VM::from_snapshot(&snapshot, std::io::empty(), std::io::sink()).is_err());
+    }
+
+    #[test]
+    fn test_frame() {
+        // 再帰で n + (n-1) + ... + 0 を求める (引数 n を結果で置き換えて返す)
+        // (0x00) PUSH 4, CALL SUM, FIN
+        // (0x05) SUM: ENTER 1, LOADL -1, STOREL 0, LOADL 0, JZ BASE, LOADL 0, SUBI 1, CALL SUM,
+        //        LOADL 0, ADD, STOREL -1, LEAVE, RET
+        // (0x1C) BASE: LEAVE, RET
+        let code = vec![
+            OpCode::Push as u8,
+            4,
+            OpCode::Call as u8,
+            0x05,
+            OpCode::Fin as u8,
+            OpCode::Enter as u8,
+            1,
+            OpCode::LoadL as u8,
+            -1i8 as u8,
+            OpCode::StoreL as u8,
+            0,
+            OpCode::LoadL as u8,
+            0,
+            OpCode::Jz as u8,
+            0x1C,
+            OpCode::LoadL as u8,
+            0,
+            OpCode::SubI as u8,
+            1,
+            OpCode::Call as u8,
+            0x05,
+            OpCode::LoadL as u8,
+            0,
+            OpCode::Add as u8,
+            OpCode::StoreL as u8,
+            -1i8 as u8,
+            OpCode::Leave as u8,
+            OpCode::Ret as u8,
+            OpCode::Leave as u8,
+            OpCode::Ret as u8,
+        ];
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+        vm.history = Some(History::new(256));
+        vm.run().unwrap();
+        assert_eq!(vm.stack, vec![10]);
+        assert_eq!(vm.fp, 0);
+        assert!(vm.call.is_empty());
+
+        // フレームポインタと STOREL も巻き戻せる
+        while vm.step_back() {}
+        assert_eq!(vm.pc, 0x00);
+        assert!(vm.stack.is_empty());
+
+        // フレームの外
+        let code = vec![OpCode::Enter as u8, 0, OpCode::LoadL as u8, 0];
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+        match vm.run() {
+            Err(VmError {
+                kind: VmErrorKind::InvalidLocal(0),
+                ..
+            }) => (),
+            _ => panic!("Expected InvalidLocal error"),
+        }
+    }
+
+    #[test]
+    fn test_div_by_zero() {
+        // 10 / 0
+        let code = vec![
+            OpCode::Push as u8,
+            10,
+            OpCode::Push as u8,
+            0,
+            OpCode::Div as u8,
+        ];
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+
+        match vm.run() {
+            Err(VmError {
+                kind: VmErrorKind::DivisionByZero,
+                ..
+            }) => (), // OK
+            _ => panic!("Expected DivisionByZero error"),
+        }
+    }
+
+    #[test]
+    fn test_profile_call_stack() {
+        // CALL 0x03, FIN, (0x03) RET
+        let code = vec![
+            OpCode::Call as u8,
+            0x03,
+            OpCode::Fin as u8,
+            OpCode::Ret as u8,
+        ];
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+        vm.profile = Some(Profile::new());
+        vm.run().unwrap();
+
+        let samples = vm.profile.unwrap().samples;
+        assert_eq!(samples.get(&vec![0x00]), Some(&2));
+        assert_eq!(samples.get(&vec![0x00, 0x03]), Some(&1));
+    }
+
+    #[test]
+    fn test_coverage_branch() {
+        // PUSH 1, JZ 0x05, FIN, (0x05) FIN
+        let code = vec![
+            OpCode::Push as u8,
+            1,
+            OpCode::Jz as u8,
+            0x05,
+            OpCode::Fin as u8,
+            OpCode::Fin as u8,
+        ];
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+        vm.coverage = Some(Coverage::new());
+        vm.run().unwrap();
+
+        let coverage = vm.coverage.unwrap();
+        assert_eq!(
+            coverage.hits.keys().copied().collect::<Vec<usize>>(),
+            vec![0, 2, 4]
+        );
+        assert_eq!(coverage.branches.get(&2), Some(&(0, 1)));
+    }
+
+    #[test]
+    fn test_step_back() {
+        // PUSH 5, STOREI 0x10, PUSH 6, STOREI 0x10, POP (underflow)
+        let code = vec![
+            OpCode::Push as u8,
+            5,
+            OpCode::StoreI as u8,
+            0x10,
+            OpCode::Push as u8,
+            6,
+            OpCode::StoreI as u8,
+            0x10,
+            OpCode::Pop as u8,
+        ];
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+        vm.history = Some(History::new(16));
+
+        match vm.run() {
+            Err(VmError {
+                kind: VmErrorKind::StackUnderflow,
+                ..
+            }) => (),
+            _ => panic!("Expected StackUnderflow error"),
+        }
+
+        assert!(vm.step_back());
+        assert_eq!(vm.pc, 0x08);
+
+        assert!(vm.rewind_to_write(0x10));
+        assert_eq!(vm.pc, 0x06);
+        assert_eq!(vm.stack, vec![6]);
+        assert_eq!(vm.memory[0x10], Some(5));
+
+        assert!(vm.rewind_to_write(0x10));
+        assert_eq!(vm.pc, 0x02);
+        assert_eq!(vm.memory[0x10], None);
+        assert!(!vm.rewind_to_write(0x10));
+    }
+
+    #[test]
+    fn test_register_syscall() {
+        // PUSH 0x10, SYSCALL, PUSH 0x00, SYSCALL
+        let code = vec![
+            OpCode::Push as u8,
+            0x10,
+            OpCode::SysCall as u8,
+            OpCode::Push as u8,
+            0x00,
+            OpCode::SysCall as u8,
+        ];
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+        vm.register_syscall(0x10, |vm: &mut VM<_, _>| vm.push_stack(42));
+        vm.unregister_syscall(syscall::SYS_READ);
+
+        match vm.run() {
+            Err(VmError {
+                kind: VmErrorKind::UnexpectedSysCall(0x00),
+                ..
+            }) => (),
+            _ => panic!("Expected UnexpectedSysCall error"),
+        }
+        assert_eq!(vm.stack, vec![42]);
+    }
+
+    #[test]
+    fn test_numeric_io() {
+        // READ_DEC, DUP, PRINT_DEC, DUP, PRINT_HEX, PRINT_BIN
+        let code = vec![
+            OpCode::Push as u8,
+            syscall::SYS_READ_DEC,
+            OpCode::SysCall as u8,
+            OpCode::Dup as u8,
+            OpCode::Push as u8,
+            syscall::SYS_PRINT_DEC,
+            OpCode::SysCall as u8,
+            OpCode::Dup as u8,
+            OpCode::Push as u8,
+            syscall::SYS_PRINT_HEX,
+            OpCode::SysCall as u8,
+            OpCode::Push as u8,
+            syscall::SYS_PRINT_BIN,
+            OpCode::SysCall as u8,
+        ];
+        let mut vm = VM::new(program(code.clone()), &b" 30\r\n"[..], Vec::new());
+        vm.run().unwrap();
+        assert_eq!(vm.out_port, b"301E11110");
+
+        let mut vm = VM::new(program(code), &b"abc\n"[..], Vec::new());
+        match vm.run() {
+            Err(VmError {
+                kind: VmErrorKind::SysError(SysError::InvalidNumber(input)),
+                ..
+            }) => assert_eq!(input, "abc"),
+            _ => panic!("Expected InvalidNumber error"),
+        }
+    }
+
+    #[test]
+    fn test_error_context() {
+        use std::error::Error;
+
+        // PUSH 1, PUSH 0, CALL 0x06, (0x06) DIV
+        let code = vec![
+            OpCode::Push as u8,
+            1,
+            OpCode::Push as u8,
+            0,
+            OpCode::Call as u8,
+            0x06,
+            OpCode::Div as u8,
+        ];
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+        let e = vm.run().unwrap_err();
+        assert!(matches!(e.kind, VmErrorKind::DivisionByZero));
+        assert_eq!(e.pc, 0x06);
+        assert_eq!(e.opcode, Some(OpCode::Div));
+        assert_eq!((e.depth, e.stack_top, e.call_depth), (2, Some(0), 1));
+        assert_eq!(e.to_string(), "Division by zero (at address 0x06, DIV)");
+        assert!(e.source().is_none());
+        assert_eq!(e.kind.exit_code(), 214);
+        assert_eq!(
+            vm.error_json(&e),
+            "{\"error\":\"runtime\",\"kind\":\"DivisionByZero\",\"message\":\"Division by zero\",\"exit_code\":214,\"pc\":6,\"opcode\":\"DIV\",\"depth\":2,\"call_depth\":1,\"stack_top\":0,\"backtrace\":[{\"addr\":6,\"label\":null,\"offset\":null,\"line\":null},{\"addr\":4,\"label\":null,\"offset\":null,\"line\":null}]}\n"
+        );
+
+        // システムコールのエラーは SysError を原因として辿れる
+        let code = vec![
+            OpCode::Push as u8,
+            syscall::SYS_READ_DEC,
+            OpCode::SysCall as u8,
+        ];
+        let mut vm = VM::new(program(code), &b"abc\n"[..], std::io::sink());
+        let e = vm.run().unwrap_err();
+        assert_eq!(e.pc, 0x02);
+        assert_eq!(e.depth, 1);
+        assert_eq!(
+            e.source().map(|source| source.to_string()),
+            Some("Invalid number: \"abc\"".to_string())
+        );
+    }
+
+    #[test]
+    fn test_print_str() {
+        // "OK" を 0x20 (長さ付き) と 0x30 (0終端) に置いて出力する
+        let mut code = Vec::new();
+        for (val, dst) in [
+            (2, 0x20),
+            (b'O', 0x21),
+            (b'K', 0x22),
+            (b'O', 0x30),
+            (b'K', 0x31),
+            (0, 0x32),
+        ] {
+            code.extend_from_slice(&[OpCode::Push as u8, val, OpCode::StoreI as u8, dst]);
+        }
+        code.extend_from_slice(&[
+            OpCode::Push as u8,
+            0x20,
+            OpCode::Push as u8,
+            syscall::SYS_PRINT_STR,
+            OpCode::SysCall as u8,
+            OpCode::Push as u8,
+            0x30,
+            OpCode::Push as u8,
+            syscall::SYS_PRINT_CSTR,
+            OpCode::SysCall as u8,
+            OpCode::Push as u8,
+            0x22,
+            OpCode::Push as u8,
+            syscall::SYS_PRINT_STR,
+            OpCode::SysCall as u8,
+        ]);
+        let mut vm = VM::new(program(code), std::io::empty(), Vec::new());
+
+        // 0x22 の 'K' (= 75) を長さとして読むと 0x23 以降は未初期化
+        match vm.run() {
+            Err(VmError {
+                kind: VmErrorKind::UninitializedMemory(0x23),
+                ..
+            }) => (),
+            _ => panic!("Expected UninitializedMemory error"),
+        }
+        assert_eq!(vm.out_port, b"OKOK");
+
+        // メモリに収まらない長さは確保する前にエラーにする
+        let mut vm = VM::<_, _, _, i64>::create(
+            vec![0x00, b'T', b'W', b'E', 0x06],
+            std::io::empty(),
+            std::io::sink(),
+            std::io::sink(),
+            VmConfig::default(),
+        );
+        vm.store_memory(i64::MAX, 0x10).unwrap();
+        assert!(matches!(
+            vm.load_str(0x10),
+            Err(VmErrorKind::InvalidMemoryAccess(addr)) if addr == vm.memory.len()
+        ));
+
+        // 0xF0 から 0x20 文字なら 0x100 番地で外に出る
+        let mut vm = VM::new(program(vec![]), std::io::empty(), std::io::sink());
+        vm.store_memory(0x20, 0xF0).unwrap();
+        assert!(matches!(
+            vm.load_str(0xF0),
+            Err(VmErrorKind::InvalidMemoryAccess(0x100))
+        ));
+    }
+
+    #[test]
+    fn test_random_seed() {
+        // RANDOM(10) を2回
+        let code = vec![
+            OpCode::Push as u8,
+            10,
+            OpCode::Push as u8,
+            syscall::SYS_RANDOM,
+            OpCode::SysCall as u8,
+            OpCode::Push as u8,
+            10,
+            OpCode::Push as u8,
+            syscall::SYS_RANDOM,
+            OpCode::SysCall as u8,
+        ];
+        let config = VmConfig {
+            seed: Some(42),
+            ..Default::default()
+        };
+        let run = || {
+            let mut vm = VM::with_config(
+                program(code.clone()),
+                std::io::empty(),
+                std::io::sink(),
+                config.clone(),
+            );
+            vm.run().unwrap();
+            vm.stack
+        };
+
+        let stack = run();
+        assert_eq!(stack, run());
+        assert!(stack.iter().all(|n| *n < 10));
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        // SLEEP 200, TIME
+        let code = vec![
+            OpCode::Push as u8,
+            200,
+            OpCode::Push as u8,
+            syscall::SYS_SLEEP,
+            OpCode::SysCall as u8,
+            OpCode::Push as u8,
+            syscall::SYS_TIME,
+            OpCode::SysCall as u8,
+        ];
+        let clock = VirtualClock::new();
+        clock.advance(Duration::from_millis(0x100));
+
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+        vm.clock = Box::new(clock.clone());
+        vm.run().unwrap();
+
+        // 0x100 + 200 = 0x1C8
+        assert_eq!(vm.stack, vec![0x00, 0x00, 0x01, 0xC8]);
+        assert_eq!(clock.now(), Duration::from_millis(0x1C8));
+    }
+
+    #[test]
+    fn test_args_and_env() {
+        // ARGC, ARGV(1) -> 0x10, GETENV("HOME") -> 0x20, GETENV("USER") -> 0x20
+        let mut code = vec![OpCode::Push as u8, syscall::SYS_ARGC, OpCode::SysCall as u8];
+        code.extend_from_slice(&[
+            OpCode::Push as u8,
+            1,
+            OpCode::Push as u8,
+            0x10,
+            OpCode::Push as u8,
+            syscall::SYS_ARGV,
+            OpCode::SysCall as u8,
+        ]);
+        for name in ["HOME", "USER"] {
+            code.extend_from_slice(&[OpCode::Push as u8, 4, OpCode::StoreI as u8, 0x40]);
+            for (i, c) in name.bytes().enumerate() {
+                code.extend_from_slice(&[
+                    OpCode::Push as u8,
+                    c,
+                    OpCode::StoreI as u8,
+                    0x41 + i as u8,
+                ]);
+            }
+            code.extend_from_slice(&[
+                OpCode::Push as u8,
+                0x40,
+                OpCode::Push as u8,
+                0x20,
+                OpCode::Push as u8,
+                syscall::SYS_GETENV,
+                OpCode::SysCall as u8,
+            ]);
+        }
+
+        let config = VmConfig {
+            args: vec!["first".to_string(), "ab".to_string()],
+            env: HashMap::from([("USER".to_string(), "twn".to_string())]),
+            ..Default::default()
+        };
+        let mut vm = VM::with_config(program(code), std::io::empty(), std::io::sink(), config);
+        vm.run().unwrap();
+
+        // argc = 2, HOME は許可されていない (1), USER は見つかる (0)
+        assert_eq!(vm.stack, vec![2, 1, 0]);
+        assert_eq!(vm.load_str(0x10).unwrap(), b"ab");
+        assert_eq!(vm.load_str(0x20).unwrap(), b"twn");
+    }
+
+    #[test]
+    fn test_send() {
+        // 時計やファイルシステムを設定した VM も別のスレッドへ移して実行できる
+        let code = vec![
+            OpCode::Push as u8,
+            10,
+            OpCode::Push as u8,
+            syscall::SYS_SLEEP,
+            OpCode::SysCall as u8,
+        ];
+        let clock = VirtualClock::new();
+        let mut vm = VM::new(program(code), std::io::empty(), Vec::new());
+        vm.clock = Box::new(clock.clone());
+        vm.fs = Some(Box::new(MemoryFileSystem::new(false)));
+
+        let vm = std::thread::spawn(move || {
+            vm.run().unwrap();
+            vm
+        })
+        .join()
+        .unwrap();
+        assert!(vm.is_finished());
+        assert_eq!(clock.now(), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_borrowed_port() {
+        // 入出力に借用したバッファを渡せる
+        let code = vec![
+            OpCode::Push as u8,
+            b'A',
+            OpCode::Push as u8,
+            syscall::SYS_PRINT,
+            OpCode::SysCall as u8,
+        ];
+        let input = [b'x'];
+        let mut output = Vec::new();
+        let mut vm = VM::new(program(code), &input[..], &mut output);
+        vm.register_syscall(0x10, |vm: &mut VM<_, _>| vm.push_stack(42));
+        vm.run().unwrap();
+        drop(vm);
+        assert_eq!(output, b"A");
+    }
+
+    #[test]
+    fn test_file_io() {
+        // "in" を読んで 1 を足し "out" に書く
+        let mut code = Vec::new();
+        for (i, c) in [2, b'i', b'n', 3, b'o', b'u', b't'].iter().enumerate() {
+            code.extend_from_slice(&[OpCode::Push as u8, *c, OpCode::StoreI as u8, 0x10 + i as u8]);
+        }
+        code.extend_from_slice(&[
+            // OPEN("in", READ) -> 0x20
+            OpCode::Push as u8,
+            0x10,
+            OpCode::Push as u8,
+            0,
+            OpCode::Push as u8,
+            syscall::SYS_OPEN,
+            OpCode::SysCall as u8,
+            OpCode::StoreI as u8,
+            0x20,
+            // OPEN("out", WRITE) -> 0x21
+            OpCode::Push as u8,
+            0x13,
+            OpCode::Push as u8,
+            1,
+            OpCode::Push as u8,
+            syscall::SYS_OPEN,
+            OpCode::SysCall as u8,
+            OpCode::StoreI as u8,
+            0x21,
+            // FREAD(0x20), 状態を捨てて +1
+            OpCode::LoadI as u8,
+            0x20,
+            OpCode::Push as u8,
+            syscall::SYS_FREAD,
+            OpCode::SysCall as u8,
+            OpCode::Pop as u8,
+            OpCode::AddI as u8,
+            1,
+            // FWRITE(val, 0x21), CLOSE(0x21)
+            OpCode::LoadI as u8,
+            0x21,
+            OpCode::Push as u8,
+            syscall::SYS_FWRITE,
+            OpCode::SysCall as u8,
+            OpCode::LoadI as u8,
+            0x21,
+            OpCode::Push as u8,
+            syscall::SYS_CLOSE,
+            OpCode::SysCall as u8,
+        ]);
+
+        let fs = MemoryFileSystem::new(true);
+        fs.insert("in", b"A");
+        let mut vm = VM::new(program(code.clone()), std::io::empty(), std::io::sink());
+        vm.fs = Some(Box::new(fs.clone()));
+        vm.run().unwrap();
+        assert_eq!(fs.get("out"), Some(b"B".to_vec()));
+
+        // 書き込みが許可されていない
+        let fs = MemoryFileSystem::new(false);
+        fs.insert("in", b"A");
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+        vm.fs = Some(Box::new(fs));
+        match vm.run() {
+            Err(VmError {
+                kind: VmErrorKind::SysError(SysError::PermissionDenied(path)),
+                ..
+            }) => assert_eq!(path, "out"),
+            _ => panic!("Expected PermissionDenied error"),
+        }
+    }
+
+    #[test]
+    fn test_dump_to_debug_port() {
+        // PUSH 0x41, STOREI 0x12, PUSH 7, CALL 0x09, FIN, (0x09) DUMP, RET
+        let code = vec![
+            OpCode::Push as u8,
+            0x41,
+            OpCode::StoreI as u8,
+            0x12,
+            OpCode::Push as u8,
+            7,
+            OpCode::Call as u8,
+            0x09,
+            OpCode::Fin as u8,
+            OpCode::Push as u8,
+            syscall::SYS_DUMP,
+            OpCode::SysCall as u8,
+            OpCode::Ret as u8,
+        ];
+
+        let mut vm = VM::with_debug_port(
+            program(code.clone()),
+            std::io::empty(),
+            std::io::sink(),
+            Vec::new(),
+            VmConfig::default(),
+        );
+        vm.debug_info = Some(DebugInfo::new(vec![(0x09, "dump".to_string())], vec![]));
+        vm.run().unwrap();
+        let text = String::from_utf8(vm.debug_port).unwrap();
+        assert!(text.contains("  [  0] 0x07   7  <- top\n"));
+        assert!(text.contains("  [  0] 0x0007\n"));
+        assert!(text.contains("PC    : 0x000B <dump+2>\n"));
+        assert!(text.contains("  0x10: -- -- 41 --"));
+        assert!(!text.contains("  0x00:"));
+
+        let config = VmConfig {
+            dump_format: DumpFormat::Json,
+            ..Default::default()
+        };
+        let mut vm = VM::with_debug_port(
+            program(code),
+            std::io::empty(),
+            std::io::sink(),
+            Vec::new(),
+            config,
+        );
+        vm.run().unwrap();
+        let json = String::from_utf8(vm.debug_port).unwrap();
+        assert!(json.starts_with(
+            "{\"pc\":11,\"fp\":0,\"flags\":{\"zero\":false,\"carry\":false,\"overflow\":false,\"negative\":false},\"stack\":[7],\"call\":[{\"addr\":7,\"label\":null,\"offset\":null,\"fp\":0}],\"memory\":[null,"
+        ));
+        assert!(json.contains(",65,"));
+    }
+
+    #[test]
+    fn test_backtrace() {
+        // (0x00) PUSH 9, CALL 0x05, FIN, (0x05) CALL 0x08, RET, (0x08) PUSH 0, DIV, RET
+        let code = vec![
+            OpCode::Push as u8,
+            9,
+            OpCode::Call as u8,
+            0x05,
+            OpCode::Fin as u8,
+            OpCode::Call as u8,
+            0x08,
+            OpCode::Ret as u8,
+            OpCode::Push as u8,
+            0,
+            OpCode::Div as u8,
+            OpCode::Ret as u8,
+        ];
+        let mut vm = VM::new(program(code), std::io::empty(), std::io::sink());
+        vm.debug_info = Some(DebugInfo::new(
+            vec![(0x00, "main".to_string()), (0x08, "inner".to_string())],
+            vec![(0x02, 2), (0x05, 5), (0x0A, 9)],
+        ));
+        let e = match vm.run() {
+            Err(
+                e @ VmError {
+                    kind: VmErrorKind::DivisionByZero,
+                    ..
+                },
+            ) => e,
+            _ => panic!("Expected division by zero"),
+        };
+
+        // DIV が取り出す前のオペランドが見える
+        assert_eq!(vm.last_pc, 0x0A);
+        assert_eq!(
+            vm.backtrace(&e),
+            "Instruction: DIV\n\
+             Stack top  : 0x00 0 (2 item(s))\n\
+             Backtrace (innermost first):\n\
+             \x20 #0 0x000A <inner+2> (line 9)\n\
+             \x20 #1 0x0005 <main+5> (line 5)\n\
+             \x20 #2 0x0002 <main+2> (line 2)\n"
+        );
+    }
 }